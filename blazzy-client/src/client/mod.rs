@@ -0,0 +1,191 @@
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use reqwest::{Method, RequestBuilder, Response, Url};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+use crate::models::{Action, Data, Event, Patterns, WatchInfo};
+use crate::subscriber::Subscriber;
+
+#[derive(Debug)]
+pub enum Error {
+    Url(String),
+    Http(reqwest::Error),
+    ///The server answered with an error status, `message` is its `error` field or the body
+    Api { status: u16, message: String },
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Url(e) => write!(f, "invalid server url {}", e),
+            Error::Http(e) => write!(f, "request failed: {}", e),
+            Error::Api { status, message } => write!(f, "server answered {}: {}", status, message),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+///Query of `/events`, every field is optional
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct EventsQuery {
+    ///Only events under this path
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<Action>,
+    ///RFC 3339 time, or a duration before now like `1h`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub until: Option<String>,
+    ///Only events with a lower id, pass the last id of a page to get the next one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub before: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+///Connection settings of a server, cheap to clone
+#[derive(Debug, Clone)]
+pub struct Client {
+    url: Url,
+    token: Option<String>,
+    http: reqwest::Client,
+    ///Connect to this unix socket instead of the host of `url`
+    #[cfg_attr(not(unix), allow(dead_code))]
+    socket: Option<PathBuf>,
+}
+
+impl Client {
+    ///`url` of the server like `http://127.0.0.1:8080`, may include a path when it is behind a proxy,
+    ///or `unix:/path/to.sock`
+    pub fn new(url: &str) -> Result<Self, Error> {
+        if let Some(path) = url.strip_prefix("unix:") {
+            return Self::unix(PathBuf::from(path));
+        }
+        let mut url = Url::parse(url).map_err(|e| Error::Url(format!("{}: {}", url, e)))?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err(Error::Url(format!("{}: expected http or https", url)));
+        }
+        // Relative paths are joined to the last segment otherwise
+        if !url.path().ends_with('/') {
+            url.set_path(&format!("{}/", url.path()));
+        }
+        Ok(Self { url, token: None, http: reqwest::Client::new(), socket: None })
+    }
+
+    #[cfg(unix)]
+    fn unix(path: PathBuf) -> Result<Self, Error> {
+        if path.as_os_str().is_empty() {
+            return Err(Error::Url("unix: missing socket path".to_string()));
+        }
+        let http = reqwest::Client::builder().unix_socket(path.clone()).build()?;
+        // The host is only sent in headers
+        Ok(Self { url: Url::parse("http://localhost/").unwrap(), token: None, http, socket: Some(path) })
+    }
+
+    #[cfg(not(unix))]
+    fn unix(path: PathBuf) -> Result<Self, Error> {
+        Err(Error::Url(format!("unix:{}: unix sockets are not supported on this platform", path.display())))
+    }
+
+    ///Sent as `Authorization: Bearer` with every request
+    pub fn token(mut self, token: impl Into<String>) -> Self {
+        self.token = Some(token.into());
+        self
+    }
+
+    ///Latest event of every path, the server must run with `-c r`
+    pub async fn latest(&self) -> Result<Vec<(PathBuf, Data)>, Error> {
+        Self::send(self.request(Method::GET, "")).await
+    }
+
+    ///Events matching `query`, newest first. Needs the SQLite backend
+    pub async fn events(&self, query: &EventsQuery) -> Result<Vec<Event>, Error> {
+        Self::send(self.request(Method::GET, "events").query(query)).await
+    }
+
+    ///Whether the server keeps a history that `events` and resumed subscriptions can read, from `/status`
+    pub async fn keeps_history(&self) -> Result<bool, Error> {
+        let status: Value = Self::send(self.request(Method::GET, "status")).await?;
+        Ok(status["history"].as_bool().unwrap_or(false))
+    }
+
+    pub async fn watches(&self) -> Result<Vec<WatchInfo>, Error> {
+        Self::send(self.request(Method::GET, "watches")).await
+    }
+
+    ///Starts observing a directory on the server, kept across reloads
+    pub async fn add_watch(&self, path: impl Into<PathBuf>, filters: Patterns) -> Result<WatchInfo, Error> {
+        let body = json!({ "path": path.into(), "filters": filters });
+        Self::send(self.request(Method::POST, "watches").json(&body)).await
+    }
+
+    pub async fn remove_watch(&self, id: u64) -> Result<(), Error> {
+        Self::check(self.request(Method::DELETE, &format!("watches/{}", id)).send().await?).await?;
+        Ok(())
+    }
+
+    ///Reloads the configuration file of the server
+    pub async fn reload(&self) -> Result<(), Error> {
+        Self::check(self.request(Method::POST, "admin/reload").send().await?).await?;
+        Ok(())
+    }
+
+    ///Subscription to the changes pushed by the server, it must run with `-c w`
+    pub fn subscribe(&self) -> Subscriber {
+        Subscriber::new(self.clone())
+    }
+
+    pub(crate) fn websocket_url(&self) -> Url {
+        let mut url = self.url.clone();
+        let scheme = if url.scheme() == "https" { "wss" } else { "ws" };
+        url.set_scheme(scheme).unwrap();
+        url
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn socket(&self) -> Option<&PathBuf> {
+        self.socket.as_ref()
+    }
+
+    pub(crate) fn bearer(&self) -> Option<String> {
+        self.token.as_ref().map(|token| format!("Bearer {}", token))
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let request = self.http.request(method, self.url.join(path).unwrap());
+        match &self.token {
+            Some(token) => request.bearer_auth(token),
+            None => request,
+        }
+    }
+
+    async fn send<T: DeserializeOwned>(request: RequestBuilder) -> Result<T, Error> {
+        Ok(Self::check(request.send().await?).await?.json().await?)
+    }
+
+    async fn check(response: Response) -> Result<Response, Error> {
+        let status = response.status();
+        if status.is_success() {
+            return Ok(response);
+        }
+        let body = response.text().await.unwrap_or_default();
+        Err(Error::Api { status: status.as_u16(), message: error_message(body) })
+    }
+}
+
+///The `error` field of a JSON error body, the body itself otherwise
+pub(crate) fn error_message(body: String) -> String {
+    serde_json::from_str::<Value>(&body).ok()
+        .and_then(|value| value.get("error")?.as_str().map(str::to_string))
+        .unwrap_or(body)
+}
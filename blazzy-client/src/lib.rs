@@ -0,0 +1,30 @@
+//!Client for a blazzy server: typed models, REST queries and a websocket subscriber that reconnects on its own.
+//!
+//!```no_run
+//!use blazzy_client::{Client, Update};
+//!use futures::StreamExt;
+//!
+//!# async fn run() -> Result<(), blazzy_client::Error> {
+//!let client = Client::new("http://127.0.0.1:8080")?.token("secret");
+//!
+//!let mut updates = client.subscribe()
+//!    .path(r"C:\projects")
+//!    .exclude("**/target/**")
+//!    .resume(true)
+//!    .stream();
+//!while let Some(update) = updates.next().await {
+//!    if let Update::Changed { path, data } = update {
+//!        println!("{:?}: {}", data.action(), path.display());
+//!    }
+//!}
+//!# Ok(())
+//!# }
+//!```
+
+pub mod models;
+mod client;
+mod subscriber;
+
+pub use client::{Client, Error, EventsQuery};
+pub use subscriber::{Subscriber, Update};
+pub use models::{Action, Data, Event, MetadataWrapper, Patterns, WatchError, WatchInfo, WatchStatus};
@@ -0,0 +1,214 @@
+//!Types sent and stored by the server, shared with it so both sides agree on the wire format
+#[cfg(feature = "server")]
+use std::fs::Metadata;
+use std::path::PathBuf;
+use std::str::FromStr;
+#[cfg(feature = "server")]
+use std::time::{Duration, Instant, SystemTime};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct MetadataWrapper {
+    pub file_type: String,
+    pub is_dir: bool,
+    pub is_file: bool,
+    pub is_symlink: bool,
+    pub len_in_bytes: u64,
+    pub permissions: String,
+    pub modified: String,
+    pub accessed: String,
+    pub created: String,
+}
+
+///Times the platform or file system doesn't record are left empty
+#[cfg(feature = "server")]
+impl From<&Metadata> for MetadataWrapper {
+    fn from(metadata: &Metadata) -> Self {
+        let time = |time: std::io::Result<SystemTime>| time.map(|time| format!("{:?}", time)).unwrap_or_default();
+        MetadataWrapper {
+            file_type: format!("{:?}", metadata.file_type()),
+            is_dir: metadata.is_dir(),
+            is_file: metadata.is_file(),
+            is_symlink: metadata.is_symlink(),
+            len_in_bytes: metadata.len(),
+            permissions: format!("{:?}", metadata.permissions()),
+            modified: time(metadata.modified()),
+            accessed: time(metadata.accessed()),
+            created: time(metadata.created()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Data {
+    action: Action,
+    metadata: Option<MetadataWrapper>,
+    ///When the observer saw the change, not kept across restarts
+    #[cfg(feature = "server")]
+    #[serde(skip)]
+    observed: Option<Instant>,
+}
+
+impl Data {
+    pub fn new(action: Action, metadata: Option<MetadataWrapper>) -> Self {
+        Self {
+            action,
+            metadata,
+            #[cfg(feature = "server")]
+            observed: None,
+        }
+    }
+
+    ///A change the observer just saw
+    #[cfg(feature = "server")]
+    pub fn observed(action: Action, metadata: Option<MetadataWrapper>) -> Self {
+        Self {
+            observed: Some(Instant::now()),
+            ..Self::new(action, metadata)
+        }
+    }
+
+    ///Time since the change was observed, `None` for events restored from disk
+    #[cfg(feature = "server")]
+    pub fn age(&self) -> Option<Duration> {
+        self.observed.map(|observed| observed.elapsed())
+    }
+
+    pub fn action(&self) -> Action {
+        self.action
+    }
+
+    pub fn metadata(&self) -> Option<&MetadataWrapper> {
+        self.metadata.as_ref()
+    }
+}
+
+impl PartialEq for Data {
+    fn eq(&self, other: &Self) -> bool {
+        self.action == other.action && self.metadata == other.metadata
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+#[serde(try_from = "String", into = "String")]
+pub enum Action {
+    Created,
+    Deleted,
+    Modified,
+    RenamedIn,
+    RenamedOut,
+    Unknown
+}
+
+impl FromStr for Action {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "created" => Ok(Action::Created),
+            "deleted" => Ok(Action::Deleted),
+            "modified" => Ok(Action::Modified),
+            "renamedin" => Ok(Action::RenamedIn),
+            "renamedout" => Ok(Action::RenamedOut),
+            "unknown" => Ok(Action::Unknown),
+            _ => Err(format!("Unknown action: {}", s)),
+        }
+    }
+}
+
+impl TryFrom<String> for Action {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<Action> for String {
+    fn from(action: Action) -> Self {
+        format!("{:?}", action)
+    }
+}
+
+///An event kept by the SQLite backend, as returned by `/events`
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub id: i64,
+    pub path: PathBuf,
+    ///RFC 3339, when the event was recorded
+    pub time: String,
+    #[serde(flatten)]
+    pub data: Data,
+}
+
+///Include/exclude globs, an empty include list keeps everything
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Patterns {
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+}
+
+///Pushed to stream clients when an observer fails
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchError {
+    pub path: PathBuf,
+    pub message: String,
+    ///Failures in a row
+    pub attempt: u32,
+    ///When the observer is started again
+    pub retry_in: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchSource {
+    ///From `roots` in the configuration, replaced on reload
+    Config,
+    ///Added with `POST /watches`, kept across reloads
+    Api,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum WatchStatus {
+    Active,
+    Failed,
+    Overflowing,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct WatchInfo {
+    pub id: u64,
+    pub path: PathBuf,
+    pub source: WatchSource,
+    pub status: WatchStatus,
+    pub events: u64,
+    pub filtered: u64,
+    pub overflows: u64,
+    pub restarts: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+///Sent to a websocket client when its queue overflowed since the last frame
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Overflow {
+    pub policy: String,
+    pub dropped: u64,
+    pub coalesced: u64,
+}
+
+///Sent to a websocket client that connected with `since`, ahead of the replayed events
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Resume {
+    Replayed {
+        ///Events recorded since then, sent oldest first
+        events: usize,
+        ///False when older events did not fit in the queue and were skipped
+        complete: bool,
+    },
+    ///The server keeps no history
+    Failed { error: String },
+}
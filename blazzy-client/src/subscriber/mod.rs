@@ -0,0 +1,323 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::time::Duration;
+use chrono::{DateTime, Utc};
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use reqwest::Url;
+use serde::Deserialize;
+use tokio_tungstenite::connect_async;
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::tungstenite::client::IntoClientRequest;
+use tokio_tungstenite::tungstenite::handshake::client::Request;
+use crate::client::{error_message, Client};
+use crate::models::{Data, Overflow, Patterns, Resume, WatchError};
+
+///A resumed subscription starts this long before the last change, changes recorded around a disconnect may be repeated
+const RESUME_MARGIN: Duration = Duration::from_secs(1);
+
+///Incoming frames, over TCP or a unix socket
+type Socket = BoxStream<'static, Result<Message, tungstenite::Error>>;
+
+///Response to the websocket handshake
+type Response = tungstenite::handshake::client::Response;
+
+///What a subscription stream yields
+#[derive(Debug, Clone, PartialEq)]
+pub enum Update {
+    ///Connected, again after `Disconnected`
+    Connected,
+    Changed { path: PathBuf, data: Data },
+    ///Sent when connecting with `since` or reconnecting with `resume`, followed by the changes recorded meanwhile
+    Resumed {
+        events: usize,
+        ///False when the server skipped older changes that did not fit in its queue
+        complete: bool,
+    },
+    ///Asked for `since` or `resume` but the server keeps no history, earlier changes are not replayed
+    ResumeFailed(String),
+    ///The server dropped or merged queued changes because this subscriber fell behind
+    Overflow(Overflow),
+    ///An observer on the server failed and is restarted
+    Failed(WatchError),
+    ///The connection was lost or could not be made, the next attempt is made after `retry_in`
+    Disconnected { reason: String, retry_in: Duration },
+    ///The server refused the subscription, for example because of a bad token or glob. The stream ends after it
+    Rejected { status: u16, reason: String },
+}
+
+///Configures a subscription, see `Client::subscribe`
+pub struct Subscriber {
+    client: Client,
+    path: Option<PathBuf>,
+    patterns: Patterns,
+    since: Option<String>,
+    resume: bool,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Subscriber {
+    pub(crate) fn new(client: Client) -> Self {
+        Self {
+            client,
+            path: None,
+            patterns: Patterns::default(),
+            since: None,
+            resume: false,
+            min_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+        }
+    }
+
+    ///Only changes under this path
+    pub fn path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.path = Some(path.into());
+        self
+    }
+
+    ///Only changes of paths matching this glob, repeat to add more
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.patterns.include.push(glob.into());
+        self
+    }
+
+    ///Drop changes of paths matching this glob
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.patterns.exclude.push(glob.into());
+        self
+    }
+
+    ///Start with the changes recorded since this RFC 3339 time or duration before now like `10m`.
+    ///Needs the SQLite backend on the server
+    pub fn since(mut self, since: impl Into<String>) -> Self {
+        self.since = Some(since.into());
+        self
+    }
+
+    ///After reconnecting, replay the changes recorded while disconnected. Needs the SQLite backend on the server,
+    ///which is asked for the changes since the last one received, by its own clock
+    pub fn resume(mut self, resume: bool) -> Self {
+        self.resume = resume;
+        self
+    }
+
+    ///Delay before the first reconnection attempt, doubled after every failed one up to `max`
+    pub fn reconnect_delay(mut self, min: Duration, max: Duration) -> Self {
+        self.min_delay = min;
+        self.max_delay = max.max(min);
+        self
+    }
+
+    ///Connects and reconnects until the stream is dropped
+    pub fn stream(self) -> BoxStream<'static, Update> {
+        let state = State {
+            delay: self.min_delay,
+            subscriber: self,
+            socket: None,
+            pending: VecDeque::new(),
+            last_seen: None,
+            retry: None,
+            done: false,
+        };
+        futures::stream::unfold(state, |mut state| async move {
+            loop {
+                if let Some(update) = state.pending.pop_front() {
+                    return Some((update, state));
+                }
+                if state.done {
+                    return None;
+                }
+                let Some(socket) = state.socket.as_mut() else {
+                    if let Some(delay) = state.retry.take() {
+                        tokio::time::sleep(delay).await;
+                    }
+                    state.connect().await;
+                    continue;
+                };
+                match socket.next().await {
+                    Some(Ok(Message::Text(text))) => {
+                        let (updates, time) = parse(&text);
+                        state.last_seen = state.last_seen.max(time);
+                        state.pending.extend(updates);
+                    }
+                    Some(Ok(Message::Close(frame))) => {
+                        let reason = frame.map(|frame| frame.reason.to_string()).filter(|reason| !reason.is_empty());
+                        state.lost(reason.unwrap_or_else(|| "closed by the server".to_string()));
+                    }
+                    Some(Ok(_)) => {}
+                    Some(Err(e)) => state.lost(e.to_string()),
+                    None => state.lost("connection closed".to_string()),
+                }
+            }
+        }).boxed()
+    }
+
+    ///The socket and the server's time from the `Date` header of the handshake
+    async fn open(&self, request: Request) -> Result<(Socket, Option<DateTime<Utc>>), tungstenite::Error> {
+        #[cfg(unix)]
+        if let Some(path) = self.client.socket() {
+            let stream = tokio::net::UnixStream::connect(path).await?;
+            let (socket, response) = tokio_tungstenite::client_async(request, stream).await?;
+            return Ok((socket.boxed(), date(&response)));
+        }
+        let (socket, response) = connect_async(request).await?;
+        Ok((socket.boxed(), date(&response)))
+    }
+
+    fn url(&self, since: Option<&str>) -> Url {
+        let mut url = self.client.websocket_url();
+        {
+            let mut query = url.query_pairs_mut();
+            if let Some(path) = &self.path {
+                query.append_pair("path", &path.to_string_lossy());
+            }
+            for glob in &self.patterns.include {
+                query.append_pair("include", glob);
+            }
+            for glob in &self.patterns.exclude {
+                query.append_pair("exclude", glob);
+            }
+            if let Some(since) = since {
+                query.append_pair("since", since);
+            }
+        }
+        if url.query() == Some("") {
+            url.set_query(None);
+        }
+        url
+    }
+}
+
+struct State {
+    subscriber: Subscriber,
+    socket: Option<Socket>,
+    pending: VecDeque<Update>,
+    ///Server time of the last change, or of the first connection, where a resumed subscription continues
+    last_seen: Option<DateTime<Utc>>,
+    ///Next reconnection delay
+    delay: Duration,
+    ///Wait before connecting
+    retry: Option<Duration>,
+    done: bool,
+}
+
+impl State {
+    async fn connect(&mut self) {
+        let since = match self.last_seen {
+            None => self.subscriber.since.clone(),
+            Some(last_seen) if self.subscriber.resume => Some((last_seen - chrono::Duration::from_std(RESUME_MARGIN).unwrap()).to_rfc3339()),
+            Some(_) => None,
+        };
+        let mut request = self.subscriber.url(since.as_deref()).as_str().into_client_request().unwrap();
+        if let Some(bearer) = self.subscriber.client.bearer() {
+            request.headers_mut().insert("Authorization", bearer.parse().unwrap());
+        }
+
+        match self.subscriber.open(request).await {
+            Ok((socket, server_time)) => {
+                self.socket = Some(socket);
+                self.delay = self.subscriber.min_delay;
+                // Servers that send no time are assumed to agree with the local clock
+                self.last_seen.get_or_insert_with(|| server_time.unwrap_or_else(Utc::now));
+                self.pending.push_back(Update::Connected);
+            }
+            Err(tungstenite::Error::Http(response)) if response.status().is_client_error() => {
+                let reason = response.body().as_ref()
+                    .map(|body| error_message(String::from_utf8_lossy(body).to_string()))
+                    .unwrap_or_else(|| response.status().to_string());
+                self.pending.push_back(Update::Rejected { status: response.status().as_u16(), reason });
+                self.done = true;
+            }
+            Err(e) => self.lost(e.to_string()),
+        }
+    }
+
+    fn lost(&mut self, reason: String) {
+        self.socket = None;
+        self.pending.push_back(Update::Disconnected { reason, retry_in: self.delay });
+        self.retry = Some(self.delay);
+        self.delay = (self.delay * 2).min(self.subscriber.max_delay);
+    }
+}
+
+fn date(response: &Response) -> Option<DateTime<Utc>> {
+    let date = response.headers().get("Date")?.to_str().ok()?;
+    DateTime::parse_from_rfc2822(date).ok().map(|date| date.with_timezone(&Utc))
+}
+
+///A change in a frame, older servers send no `time`
+#[derive(Deserialize)]
+struct Change {
+    #[serde(flatten)]
+    data: Data,
+    time: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Frame {
+    Changes(Vec<HashMap<PathBuf, Change>>),
+    Overflow { overflow: Overflow },
+    Error { error: WatchError },
+    Resume { resume: Resume },
+}
+
+///Updates in a text frame and the time of its latest change. Frames this version doesn't know are skipped
+///so newer servers keep working
+fn parse(text: &str) -> (Vec<Update>, Option<DateTime<Utc>>) {
+    let updates = match serde_json::from_str::<Frame>(text) {
+        Ok(Frame::Changes(changes)) => {
+            let mut latest = None;
+            let updates = changes.into_iter()
+                .flatten()
+                .map(|(path, change)| {
+                    let time = change.time.and_then(|time| DateTime::parse_from_rfc3339(&time).ok());
+                    latest = latest.max(time.map(|time| time.with_timezone(&Utc)));
+                    Update::Changed { path, data: change.data }
+                })
+                .collect();
+            return (updates, latest);
+        }
+        Ok(Frame::Overflow { overflow }) => vec![Update::Overflow(overflow)],
+        Ok(Frame::Error { error }) => vec![Update::Failed(error)],
+        Ok(Frame::Resume { resume: Resume::Replayed { events, complete } }) => vec![Update::Resumed { events, complete }],
+        Ok(Frame::Resume { resume: Resume::Failed { error } }) => vec![Update::ResumeFailed(error)],
+        // "Connected"
+        Err(_) => vec![],
+    };
+    (updates, None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::client::Client;
+    use crate::models::{Action, Data};
+    use crate::subscriber::{parse, Update};
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("Connected"), (vec![], None));
+        let (updates, time) = parse(r#"[{"/srv/a": {"action": "Created", "metadata": null, "time": "2024-05-01T12:00:02+02:00"}}, {"/srv/b": {"action": "Deleted", "metadata": null, "time": "2024-05-01T12:00:01+02:00"}}]"#);
+        assert_eq!(updates, vec![
+            Update::Changed { path: PathBuf::from("/srv/a"), data: Data::new(Action::Created, None) },
+            Update::Changed { path: PathBuf::from("/srv/b"), data: Data::new(Action::Deleted, None) },
+        ]);
+        assert_eq!(time.unwrap().to_rfc3339(), "2024-05-01T10:00:02+00:00");
+        // Servers from before frames had times
+        assert_eq!(parse(r#"[{"/srv/a": {"action": "Created", "metadata": null}}]"#).1, None);
+        assert!(matches!(parse(r#"{"overflow": {"policy": "drop-oldest", "dropped": 3, "coalesced": 0}}"#).0[..], [Update::Overflow(ref overflow)] if overflow.dropped == 3));
+        assert!(matches!(parse(r#"{"error": {"path": "/srv", "message": "gone", "attempt": 1, "retry_in": "1s"}}"#).0[..], [Update::Failed(_)]));
+        assert_eq!(parse(r#"{"resume": {"events": 2, "complete": true}}"#).0, vec![Update::Resumed { events: 2, complete: true }]);
+        assert_eq!(parse(r#"{"resume": {"error": "no history"}}"#).0, vec![Update::ResumeFailed("no history".to_string())]);
+    }
+
+    #[test]
+    fn url_test() {
+        let client = Client::new("https://example.com/blazzy").unwrap();
+        assert_eq!(client.subscribe().url(None).as_str(), "wss://example.com/blazzy/");
+        let subscriber = client.subscribe().path("/srv").include("**/*.rs").exclude("**/target/**");
+        assert_eq!(subscriber.url(None).as_str(), "wss://example.com/blazzy/?path=%2Fsrv&include=**%2F*.rs&exclude=**%2Ftarget%2F**");
+    }
+}
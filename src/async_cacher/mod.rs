@@ -1,9 +1,15 @@
 use std::collections::HashMap;
 use std::path::PathBuf;
-use tokio::sync::{mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, Mutex};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use tokio::sync::oneshot;
 use crate::observer::Data;
+use crate::wal::Wal;
 use std::sync::Arc;
 use lazy_static::lazy_static;
+use tracing::error;
+use crate::config::Retention;
+use crate::status::SharedStatus;
+use crate::storage::{Event, HistoryQuery, MemoryStore, Store};
 
 lazy_static!{
     pub static ref SharedAsyncCacher: Arc<AsyncCacher> = Arc::new(AsyncCacher::init());
@@ -11,104 +17,146 @@ lazy_static!{
 
 pub struct AsyncCacher {
     tx: UnboundedSender<AsyncReq>,
-    rx: Arc<Mutex<UnboundedReceiver<AsyncRes>>>,
 }
 
 impl AsyncCacher {
     pub fn init() -> Self {
         let (tx, mut rx) = unbounded_channel();
-        let (tx_, rx_) = unbounded_channel();
-        let rx_ = Arc::new(Mutex::new(rx_));
 
-        let cacher = Self {
-            tx: tx.clone(),
-            rx: rx_.clone(),
-        };
-
-        tokio::task::spawn(async move {
-            let mut map = HashMap::new();
-            while let Some(action) = rx.recv().await {
+        // SQLite and the WAL block, so the store gets its own thread instead of a runtime worker
+        std::thread::Builder::new().name("blazzy-cache".to_string()).spawn(move || {
+            let mut store: Box<dyn Store> = Box::new(MemoryStore::new());
+            let mut wal: Option<Wal> = None;
+            // A requester that went away doesn't wait for its reply, so failed sends are ignored
+            while let Some(action) = rx.blocking_recv() {
                 match action {
                     AsyncReq::Put(p, d) => {
-                        map.insert(p, d);
+                        if let Some(wal) = wal.as_mut() {
+                            if let Err(e) = wal.append(&p, &d) {
+                                error!(error = %e, "Failed to write to the WAL");
+                                SharedStatus.error(format!("failed to write to the WAL: {}", e));
+                            }
+                        }
+                        store.put(p, d);
+                    }
+                    AsyncReq::SetStore(s) => {
+                        store = s;
                     }
-                    AsyncReq::Get => {
-                        tx_.clone().send(AsyncRes::Get(map.clone())).unwrap();
+                    AsyncReq::AttachWal(w) => {
+                        wal = Some(w);
                     }
-                    AsyncReq::Pop => {
-                        let item = map.iter().last();
-                        if let Some((key, value)) = item {
-                            tx_.clone().send(AsyncRes::Pop(Some((key.clone(), value.clone())))).unwrap();
-                            map.remove(&key.clone());
-                        } else {
-                            tx_.clone().send(AsyncRes::Pop(None)).unwrap();
+                    AsyncReq::Checkpoint(reply) => {
+                        if let Some(Err(e)) = wal.as_mut().map(|wal| wal.rotate()) {
+                            error!(error = %e, "Failed to rotate the WAL");
                         }
+                        let _ = reply.send(store.latest());
+                    }
+                    AsyncReq::CheckpointSaved => {
+                        if let Some(Err(e)) = wal.as_mut().map(|wal| wal.truncate()) {
+                            error!(error = %e, "Failed to truncate the WAL");
+                        }
+                    }
+                    AsyncReq::Get(reply) => {
+                        let _ = reply.send(store.latest());
                     }
-                    AsyncReq::IsEmpty => {
-                        tx_.send(AsyncRes::IsEmpty(map.is_empty())).unwrap();
+                    AsyncReq::Pop(reply) => {
+                        let _ = reply.send(store.pop_oldest());
+                    }
+                    AsyncReq::SetRetention(retention) => {
+                        store.set_retention(&retention);
+                    }
+                    AsyncReq::IsEmpty(reply) => {
+                        let _ = reply.send(store.is_empty());
+                    }
+                    AsyncReq::Len(reply) => {
+                        let _ = reply.send(store.len());
+                    }
+                    AsyncReq::KeepsHistory(reply) => {
+                        let _ = reply.send(Ok(store.keeps_history()));
+                    }
+                    AsyncReq::History(query, reply) => {
+                        let _ = reply.send(store.history(&query));
                     }
                 }
             }
-        });
+        }).expect("Failed to start the cache thread");
+
+        Self { tx }
+    }
+
+    ///Sends a request carrying its own reply channel
+    async fn ask<T>(&self, request: impl FnOnce(oneshot::Sender<Result<T, String>>) -> AsyncReq) -> Result<T, String> {
+        let (reply, response) = oneshot::channel();
+        self.tx.send(request(reply)).map_err(|_| "cache is not running".to_string())?;
+        response.await.map_err(|_| "cache is not running".to_string())?
+    }
+
+    ///Replaces the in-memory store, call before anything is put
+    pub fn set_store(&self, store: Box<dyn Store>) {
+        self.tx.send(AsyncReq::SetStore(store)).unwrap();
+    }
+
+    pub fn set_retention(&self, retention: Retention) {
+        self.tx.send(AsyncReq::SetRetention(retention)).unwrap();
+    }
+
+    ///False for the in-memory store, which only has the latest event of every path
+    pub async fn keeps_history(&self) -> Result<bool, String> {
+        self.ask(AsyncReq::KeepsHistory).await
+    }
+
+    pub async fn history(&self, query: HistoryQuery) -> Result<Vec<Event>, String> {
+        self.ask(|reply| AsyncReq::History(query, reply)).await
+    }
+
+    ///Every following `put` is logged to `wal` before it is applied
+    pub fn attach_wal(&self, wal: Wal) {
+        self.tx.send(AsyncReq::AttachWal(wal)).unwrap();
+    }
+
+    ///Snapshot to save, the WAL keeps the events from before it until `checkpoint_saved`
+    pub async fn checkpoint(&self) -> Result<HashMap<PathBuf, Data>, String> {
+        self.ask(AsyncReq::Checkpoint).await
+    }
 
-        cacher
+    pub fn checkpoint_saved(&self) {
+        self.tx.send(AsyncReq::CheckpointSaved).unwrap();
     }
 
     pub fn put(&self, path_buf: PathBuf, data: Data) {
         self.tx.send(AsyncReq::Put(path_buf, data)).unwrap();
     }
 
-    pub async fn get(&self) -> HashMap<PathBuf, Data> {
-        self.tx.send(AsyncReq::Get).unwrap();
-        let mut l = HashMap::new();
-        let mut rx = self.rx.lock().await;
-        while let Some(res) = rx.recv().await {
-            if let AsyncRes::Get(lru) = res {
-                l = lru;
-                break;
-            }
-        }
-        l
+    pub async fn get(&self) -> Result<HashMap<PathBuf, Data>, String> {
+        self.ask(AsyncReq::Get).await
     }
 
-    pub async fn pop(&self) -> Option<(PathBuf, Data)> {
-        self.tx.send(AsyncReq::Pop).unwrap();
-        let mut l = None;
-        let mut rx = self.rx.lock().await;
-        while let Some(res) = rx.recv().await {
-            if let AsyncRes::Pop(lru) = res {
-                l = lru;
-                break;
-            }
-        }
-        l
+    pub async fn pop(&self) -> Result<Option<(PathBuf, Data)>, String> {
+        self.ask(AsyncReq::Pop).await
     }
 
-    pub async fn is_empty(&self) -> bool {
-        self.tx.send(AsyncReq::IsEmpty).unwrap();
-        let mut is_empty = false;
-        let mut rx = self.rx.lock().await;
-        while let Some(res) = rx.recv().await {
-            if let AsyncRes::IsEmpty(b) = res {
-                is_empty = b;
-                break;
-            }
-        }
-        is_empty
+    pub async fn is_empty(&self) -> Result<bool, String> {
+        self.ask(AsyncReq::IsEmpty).await
+    }
+
+    pub async fn len(&self) -> Result<usize, String> {
+        self.ask(AsyncReq::Len).await
     }
 }
 
 enum AsyncReq {
     Put(PathBuf, Data),
-    Get,
-    Pop,
-    IsEmpty,
-}
-
-enum AsyncRes {
-    Get(HashMap<PathBuf, Data>),
-    IsEmpty(bool),
-    Pop(Option<(PathBuf, Data)>),
+    Get(oneshot::Sender<Result<HashMap<PathBuf, Data>, String>>),
+    Pop(oneshot::Sender<Result<Option<(PathBuf, Data)>, String>>),
+    IsEmpty(oneshot::Sender<Result<bool, String>>),
+    Len(oneshot::Sender<Result<usize, String>>),
+    SetRetention(Retention),
+    SetStore(Box<dyn Store>),
+    KeepsHistory(oneshot::Sender<Result<bool, String>>),
+    History(HistoryQuery, oneshot::Sender<Result<Vec<Event>, String>>),
+    AttachWal(Wal),
+    Checkpoint(oneshot::Sender<Result<HashMap<PathBuf, Data>, String>>),
+    CheckpointSaved,
 }
 
 #[cfg(test)]
@@ -116,22 +164,64 @@ mod tests {
     use std::path::PathBuf;
     use crate::async_cacher::AsyncCacher;
     use crate::observer::{Action, Data};
+    use crate::storage::{HistoryQuery, SqliteStore};
+    use crate::wal::Wal;
 
     #[tokio::test]
     async fn put_and_get_test() {
-        let cacher = AsyncCacher::init().await;
+        let cacher = AsyncCacher::init();
         cacher.put(PathBuf::new(), Data::new(Action::Created, None));
-        for (key, value) in cacher.get().await {
+        for (key, value) in cacher.get().await.unwrap() {
             assert_eq!((key, value), (PathBuf::new(), Data::new(Action::Created, None)));
         }
     }
 
     #[tokio::test]
     async fn pop_test() {
-        let cacher = AsyncCacher::init().await;
+        let cacher = AsyncCacher::init();
         cacher.put(PathBuf::new(), Data::new(Action::Created, None));
-        let item = cacher.pop().await;
+        let item = cacher.pop().await.unwrap();
         assert_eq!(item, Some((PathBuf::new(), Data::new(Action::Created, None))));
-        assert_eq!(cacher.is_empty().await, true);
+        assert_eq!(cacher.is_empty().await.unwrap(), true);
+    }
+
+    #[tokio::test]
+    async fn checkpoint_test() {
+        let dir = std::env::temp_dir().join(format!("blazzy-cacher-{}", std::process::id()));
+        let path = dir.join("state.wal");
+        let cacher = AsyncCacher::init();
+        cacher.attach_wal(Wal::open(path.clone(), false).unwrap());
+
+        cacher.put(PathBuf::from("/a"), Data::new(Action::Created, None));
+        let snapshot = cacher.checkpoint().await.unwrap();
+        assert_eq!(snapshot.len(), 1);
+        cacher.put(PathBuf::from("/b"), Data::new(Action::Modified, None));
+        cacher.checkpoint_saved();
+
+        // Requests are handled in order, once this answers the WAL only holds what came after the snapshot
+        assert_eq!(cacher.len().await.unwrap(), 2);
+        assert_eq!(Wal::open(path, false).unwrap().replay().unwrap(), vec![(PathBuf::from("/b"), Data::new(Action::Modified, None))]);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn history_test() {
+        let query = HistoryQuery { limit: 10, ..HistoryQuery::default() };
+        let cacher = AsyncCacher::init();
+        assert!(!cacher.keeps_history().await.unwrap());
+        assert!(cacher.history(query.clone()).await.is_err());
+
+        let path = std::env::temp_dir().join(format!("blazzy-cacher-{}.db", std::process::id()));
+        cacher.set_store(Box::new(SqliteStore::open(&path).unwrap()));
+        assert!(cacher.keeps_history().await.unwrap());
+        cacher.put(PathBuf::from("/a"), Data::new(Action::Created, None));
+        cacher.put(PathBuf::from("/a"), Data::new(Action::Deleted, None));
+        let events = cacher.history(query).await.unwrap();
+        assert_eq!(events.iter().map(|event| event.data.action()).collect::<Vec<_>>(), vec![Action::Deleted, Action::Created]);
+
+        drop(cacher);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
     }
 }
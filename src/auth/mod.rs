@@ -0,0 +1,158 @@
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use actix_web::{Error, HttpMessage, web};
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::{ErrorForbidden, ErrorUnauthorized};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use serde::Deserialize;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    ///Only GET/HEAD requests and stream subscriptions
+    Read,
+    ///Everything, including endpoints that change server state
+    Admin,
+}
+
+///What a token is allowed to see
+#[derive(Debug, Clone, PartialEq)]
+pub struct Scope {
+    pub access: Access,
+    ///Path prefixes visible to the token, empty means every watched path
+    pub prefixes: Vec<PathBuf>,
+}
+
+impl Scope {
+    pub fn full() -> Self {
+        Self { access: Access::Admin, prefixes: vec![] }
+    }
+
+    pub fn allows(&self, path: &Path) -> bool {
+        self.prefixes.is_empty() || self.prefixes.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+///Token given as `[admin:]TOKEN[=PREFIX,...]`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub struct Token {
+    secret: String,
+    scope: Scope,
+}
+
+impl FromStr for Token {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (secret, prefixes) = match s.split_once('=') {
+            Some((secret, prefixes)) => (secret, prefixes.split(',').filter(|p| !p.is_empty()).map(PathBuf::from).collect()),
+            None => (s, vec![]),
+        };
+        let (access, secret) = match secret.strip_prefix("admin:") {
+            Some(secret) => (Access::Admin, secret),
+            None => (Access::Read, secret),
+        };
+        if secret.is_empty() {
+            return Err("token must not be empty".to_string());
+        }
+        Ok(Self {
+            secret: secret.to_string(),
+            scope: Scope { access, prefixes },
+        })
+    }
+}
+
+impl TryFrom<String> for Token {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Auth {
+    tokens: Vec<Token>,
+}
+
+impl Auth {
+    pub fn new(tokens: Vec<Token>) -> Self {
+        Self { tokens }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    fn scope_for(&self, presented: &str) -> Option<Scope> {
+        self.tokens.iter()
+            .find(|token| constant_time_eq(token.secret.as_bytes(), presented.as_bytes()))
+            .map(|token| token.scope.clone())
+    }
+
+    ///Token from `Authorization: Bearer`, `X-Api-Key` or the `token` query parameter (browsers can't set headers on websockets)
+    fn presented(req: &ServiceRequest) -> Option<String> {
+        let headers = req.headers();
+        if let Some(bearer) = headers.get("Authorization").and_then(|h| h.to_str().ok()).and_then(|h| h.strip_prefix("Bearer ")) {
+            return Some(bearer.trim().to_string());
+        }
+        if let Some(key) = headers.get("X-Api-Key").and_then(|h| h.to_str().ok()) {
+            return Some(key.trim().to_string());
+        }
+        web::Query::<Vec<(String, String)>>::from_query(req.query_string()).ok()
+            .and_then(|query| query.0.into_iter().find(|(k, _)| k == "token").map(|(_, v)| v))
+    }
+}
+
+///Resolves the caller's `Scope` and stores it in the request extensions for handlers
+pub async fn authenticate(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let auth = req.app_data::<web::Data<Auth>>().cloned().unwrap_or_default();
+
+    let scope = if auth.is_enabled() {
+        let scope = Auth::presented(&req)
+            .and_then(|presented| auth.scope_for(&presented))
+            .ok_or_else(|| ErrorUnauthorized("Missing or invalid token"))?;
+        if scope.access == Access::Read && ![Method::GET, Method::HEAD].contains(req.method()) {
+            return Err(ErrorForbidden("Token is read-only"));
+        }
+        scope
+    } else {
+        Scope::full()
+    };
+
+    req.extensions_mut().insert(scope);
+    next.call(req).await
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use crate::auth::{Access, Token};
+
+    #[test]
+    fn parse_token_test() {
+        let token = "admin:s3cret=C:\\src,C:\\docs".parse::<Token>().unwrap();
+        assert_eq!(token.secret, "s3cret");
+        assert_eq!(token.scope.access, Access::Admin);
+        assert_eq!(token.scope.prefixes, vec![PathBuf::from("C:\\src"), PathBuf::from("C:\\docs")]);
+
+        let token = "s3cret".parse::<Token>().unwrap();
+        assert_eq!(token.scope.access, Access::Read);
+        assert!(token.scope.allows(Path::new("anything")));
+        assert!("admin:".parse::<Token>().is_err());
+    }
+
+    #[test]
+    fn scope_prefix_test() {
+        let token = "s3cret=/srv/www".parse::<Token>().unwrap();
+        assert!(token.scope.allows(Path::new("/srv/www/index.html")));
+        assert!(!token.scope.allows(Path::new("/srv/www2/index.html")));
+        assert!(!token.scope.allows(Path::new("/etc/passwd")));
+    }
+}
@@ -1,51 +1,280 @@
-use clap::Parser;
-use crate::server::ConnectionType;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use crate::auth::Token;
+use crate::config::{Backend, Config, ConfigError, Root, Tls};
+use crate::duration::HumanDuration;
+use crate::export::{ExportFilter, ExportFormat};
+use crate::filter::{Filter, Patterns};
+use crate::hooks::Hook;
+use crate::logging::{LogFormat, LogRotation};
+use crate::server::{ConnectionType, Listener};
+use crate::state::StateFormat;
+use crate::tail::TailArgs;
+use crate::webhook::Webhook;
+use crate::websocket::OverflowPolicy;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
 #[warn(clippy::upper_case_acronyms)]
 pub struct CLI {
-    ///Observing path
+    #[command(subcommand)]
+    command: Option<Command>,
+    ///Configuration file (TOML, or YAML for .yaml/.yml), flags override its values
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    ///Observing path, repeat to observe several
     #[arg(short, long)]
-    path: String,
-    ///Print logs {action}: {filepath}
+    path: Vec<PathBuf>,
+    ///Only keep paths matching this glob, repeatable
+    #[arg(long)]
+    include: Vec<String>,
+    ///Drop paths matching this glob, repeatable
+    #[arg(long)]
+    exclude: Vec<String>,
+    ///Log every observed event
     #[arg(short, long)]
     logs: bool,
-    ///Server address
-    #[arg(long, default_value = "127.0.0.1:8080")]
-    host: String,
+    ///Log level or filter directives, e.g. debug or info,blazzy::webhook=debug (RUST_LOG takes precedence) [default: info]
+    #[arg(long)]
+    log_level: Option<String>,
+    ///Log line format [default: text]
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+    ///Write logs to this file instead of stdout
+    #[arg(long)]
+    log_file: Option<PathBuf>,
+    ///How often --log-file is rotated [default: daily]
+    #[arg(long, value_enum)]
+    log_rotation: Option<LogRotation>,
+    ///How many rotated log files to keep [default: all]
+    #[arg(long)]
+    log_max_files: Option<usize>,
+    ///Server address, repeat or separate with commas to listen on several (host:port, [ipv6]:port or unix:/path/to.sock) [default: 127.0.0.1:8080]
+    #[arg(long, value_delimiter = ',')]
+    host: Vec<Listener>,
+    ///Permissions of unix socket files, in octal [default: 660]
+    #[arg(long)]
+    socket_mode: Option<String>,
+    ///PEM certificate chain, enables TLS on TCP listeners (reloaded on SIGHUP)
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+    ///PEM private key for --tls-cert
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+    ///How long to wait for observers, clients and requests to finish on shutdown [default: 10s]
+    #[arg(long)]
+    shutdown_timeout: Option<HumanDuration>,
+    ///Require this token on every request, repeatable ([admin:]TOKEN[=PREFIX,...])
+    #[arg(long = "token")]
+    tokens: Vec<Token>,
+    ///POST batches of events to this endpoint, repeatable (URL[;secret=SECRET][;path=PREFIX,...][;action=ACTION,...])
+    #[arg(long = "webhook")]
+    webhooks: Vec<Webhook>,
+    ///Max events in one webhook request [default: 100]
+    #[arg(long)]
+    webhook_batch_size: Option<usize>,
+    ///How long to collect events before sending a webhook batch [default: 1s]
+    #[arg(long)]
+    webhook_flush_interval: Option<HumanDuration>,
+    ///Upper bound of the delay between webhook retries [default: 5m]
+    #[arg(long)]
+    webhook_max_backoff: Option<HumanDuration>,
+    ///Where undelivered webhook batches are kept [default: `webhooks` next to the binary]
+    #[arg(long)]
+    webhook_spool_dir: Option<PathBuf>,
+    ///Run a command for matching events, repeatable (GLOB[;action=ACTION,...];run=COMMAND [ARGS...])
+    #[arg(long = "hook")]
+    hooks: Vec<Hook>,
+    ///How long a path must stay quiet before its hooks run [default: 500ms]
+    #[arg(long)]
+    hook_debounce: Option<HumanDuration>,
+    ///Max hook commands running at once [default: 4]
+    #[arg(long)]
+    hook_concurrency: Option<usize>,
+    ///Kill hook commands running longer than this [default: 1m]
+    #[arg(long)]
+    hook_timeout: Option<HumanDuration>,
     ///Auto save state to avoid critical failures
     #[arg(short, long)]
     autosave: bool,
-    #[arg(short='d', long, default_value = "5:min")]
-    autosave_delay: String,
+    ///Interval between autosaves, e.g. 90s, 5m, 1h30m or PT10M [default: 5m]
+    #[arg(short='d', long)]
+    autosave_delay: Option<HumanDuration>,
+    ///Where the state is saved [default: `state.<json|bin|cbor>` next to the binary]
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    ///Encoding of the state file, any format is read back on startup [default: json]
+    #[arg(long, value_enum)]
+    state_format: Option<StateFormat>,
+    ///How many previous state files to keep as <state-file>.1 to <state-file>.N [default: 3]
+    #[arg(long)]
+    state_backups: Option<usize>,
+    ///Log every event to a write-ahead log, replayed on startup so a crash loses nothing since the last save
+    #[arg(long)]
+    wal: bool,
+    ///Where the write-ahead log is kept [default: <state-file>.wal]
+    #[arg(long)]
+    wal_file: Option<PathBuf>,
+    ///fsync the write-ahead log after every event
+    #[arg(long)]
+    wal_sync: bool,
+    ///Where events are kept, sqlite keeps the full history and serves /events [default: memory]
+    #[arg(long, value_enum)]
+    storage: Option<Backend>,
+    ///Database file for --storage sqlite [default: `blazzy.db` next to the binary]
+    #[arg(long)]
+    storage_path: Option<PathBuf>,
+    ///Memory: evict the least recently changed paths above this many cached entries. SQLite: keep this many events
+    #[arg(long)]
+    max_entries: Option<usize>,
+    ///SQLite: delete events older than this, e.g. 30d
+    #[arg(long)]
+    max_age: Option<HumanDuration>,
     ///Connection type (w - Websocket, r - REST)
     #[arg(short,long)]
-    connection_type: char
+    connection_type: Option<char>,
+    ///Max events queued per websocket client before the overflow policy applies [default: 1024]
+    #[arg(long)]
+    ws_queue_size: Option<usize>,
+    ///Max events sent to a websocket client in one frame [default: 100]
+    #[arg(long)]
+    ws_batch_size: Option<usize>,
+    ///Interval between websocket frames [default: 10ms]
+    #[arg(long)]
+    ws_flush_interval: Option<HumanDuration>,
+    ///What to do when a websocket client falls behind [default: drop-oldest]
+    #[arg(long, value_enum)]
+    ws_overflow_policy: Option<OverflowPolicy>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum Command {
+    ///Work with the configuration file
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    ///Write the stored events to a file or stdout, from the database or the state file and WAL
+    Export {
+        #[arg(long, value_enum, default_value = "ndjson")]
+        format: ExportFormat,
+        ///Output file [default: stdout]
+        #[arg(short, long)]
+        output: Option<PathBuf>,
+        #[command(flatten)]
+        filter: ExportFilter,
+    },
+    ///Print the changes of a running server as they happen, like `tail -f`
+    Tail(TailArgs),
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum ConfigCommand {
+    ///Check the file given with --config together with the other flags and report errors
+    Validate,
 }
 
 impl CLI {
-    pub fn get_path(&self) -> String {
-        self.path.clone()
-    }
-    pub fn get_host(&self) -> (String, u16) {
-        let s = self.host.split(":").collect::<Vec<&str>>();
-        (s[0].to_string(), s[1].parse::<u16>().unwrap())
+    pub fn get_command(&self) -> Option<Command> {
+        self.command.clone()
     }
-    pub fn with_logs(&self) -> bool {
-        self.logs
+
+    pub fn get_config_path(&self) -> Option<PathBuf> {
+        self.config.clone()
     }
-    pub fn with_autosave(&self) -> bool {
-        self.autosave
+
+    ///Loads `--config` when given and applies the flags on top of it
+    pub fn load_config(&self) -> Result<Config, ConfigError> {
+        let config = self.load_settings()?;
+        config.validate()?;
+        Ok(config)
     }
-    pub fn autosave_delay(&self) -> String {
-        self.autosave_delay.clone()
+
+    ///Like `load_config` for commands that don't observe or serve, roots and connection type may be missing
+    pub fn load_settings(&self) -> Result<Config, ConfigError> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        self.apply(&mut config)?;
+        Ok(config)
     }
-    pub fn get_connection_type(&self) -> ConnectionType {
-        match self.connection_type {
-            'w' => ConnectionType::Websocket,
-            'r' => ConnectionType::REST,
-            _ => ConnectionType::Unknown
+
+    fn apply(&self, config: &mut Config) -> Result<(), ConfigError> {
+        if !self.path.is_empty() {
+            config.roots = self.path.iter().cloned().map(Root::new).collect();
+        }
+        if !self.include.is_empty() || !self.exclude.is_empty() {
+            config.filters = Filter::new(Patterns { include: self.include.clone(), exclude: self.exclude.clone() })
+                .map_err(|e| ConfigError::Invalid(e.to_string()))?;
+        }
+        config.logs |= self.logs;
+        if let Some(level) = &self.log_level { config.log.level = level.clone(); }
+        if let Some(format) = self.log_format { config.log.format = format; }
+        if let Some(file) = &self.log_file { config.log.file = Some(file.clone()); }
+        if let Some(rotation) = self.log_rotation { config.log.rotation = rotation; }
+        if let Some(max_files) = self.log_max_files { config.log.max_files = Some(max_files); }
+
+        if !self.host.is_empty() {
+            config.server.listen = self.host.clone();
+        }
+        if let Some(mode) = &self.socket_mode {
+            config.server.socket_mode = u32::from_str_radix(mode, 8)
+                .map_err(|_| ConfigError::Invalid(format!("--socket-mode `{}` is not an octal mode", mode)))?;
+        }
+        if let Some((cert, key)) = self.tls_cert.clone().zip(self.tls_key.clone()) {
+            config.server.tls = Some(Tls { cert, key });
+        }
+        if let Some(timeout) = self.shutdown_timeout { config.server.shutdown_timeout = timeout.into(); }
+        if !self.tokens.is_empty() {
+            config.server.tokens = self.tokens.clone();
+        }
+        if let Some(connection_type) = self.connection_type {
+            config.server.connection_type = Some(match connection_type {
+                'w' => ConnectionType::Websocket,
+                'r' => ConnectionType::REST,
+                _ => ConnectionType::Unknown
+            });
+        }
+        let ws = &mut config.server.websocket;
+        if let Some(queue_size) = self.ws_queue_size { ws.queue_size = queue_size; }
+        if let Some(batch_size) = self.ws_batch_size { ws.batch_size = batch_size; }
+        if let Some(interval) = self.ws_flush_interval { ws.flush_interval = interval.into(); }
+        if let Some(policy) = self.ws_overflow_policy { ws.overflow_policy = policy; }
+
+        if !self.webhooks.is_empty() {
+            config.webhooks = self.webhooks.clone();
+        }
+        let webhook = &mut config.webhook_options;
+        if let Some(batch_size) = self.webhook_batch_size { webhook.batch_size = batch_size; }
+        if let Some(interval) = self.webhook_flush_interval { webhook.flush_interval = interval.into(); }
+        if let Some(backoff) = self.webhook_max_backoff { webhook.max_backoff = backoff.into(); }
+        if let Some(dir) = &self.webhook_spool_dir { webhook.spool_dir = dir.clone(); }
+
+        if !self.hooks.is_empty() {
+            config.hooks = self.hooks.clone();
+        }
+        let hook = &mut config.hook_options;
+        if let Some(debounce) = self.hook_debounce { hook.debounce = debounce.into(); }
+        if let Some(concurrency) = self.hook_concurrency { hook.concurrency = concurrency; }
+        if let Some(timeout) = self.hook_timeout { hook.timeout = timeout.into(); }
+
+        config.autosave.enabled |= self.autosave;
+        if let Some(delay) = self.autosave_delay {
+            config.autosave.delay = delay.into();
+        }
+        if let Some(file) = &self.state_file { config.state.file = Some(file.clone()); }
+        if let Some(format) = self.state_format { config.state.format = format; }
+        if let Some(backups) = self.state_backups { config.state.backups = backups; }
+        config.wal.enabled |= self.wal || self.wal_file.is_some() || self.wal_sync;
+        if let Some(file) = &self.wal_file { config.wal.file = Some(file.clone()); }
+        config.wal.sync |= self.wal_sync;
+        if let Some(backend) = self.storage { config.storage.backend = backend; }
+        if let Some(path) = &self.storage_path { config.storage.path = Some(path.clone()); }
+        if let Some(max_entries) = self.max_entries {
+            config.retention.max_entries = Some(max_entries);
         }
+        if let Some(max_age) = self.max_age { config.retention.max_age = Some(max_age); }
+        Ok(())
     }
 }
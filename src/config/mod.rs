@@ -0,0 +1,302 @@
+use std::fmt::{Display, Formatter};
+use std::{env, fs};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use clap::ValueEnum;
+use serde::{Deserialize, Deserializer};
+use crate::auth::Token;
+use crate::duration::HumanDuration;
+use crate::filter::Filter;
+use crate::hooks::{Hook, HookOptions};
+use crate::logging::LogOptions;
+use crate::server::{ConnectionType, Listener};
+use crate::state::StateOptions;
+use crate::wal::WalOptions;
+use crate::webhook::{Webhook, WebhookOptions};
+use crate::websocket::WsOptions;
+
+///Everything blazzy needs to run, read from `--config` (TOML or YAML) and overridden by CLI flags
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    ///Log every observed event
+    pub logs: bool,
+    pub log: LogOptions,
+    pub roots: Vec<Root>,
+    ///Applied to every root in addition to its own filters
+    pub filters: Filter,
+    pub storage: Storage,
+    pub retention: Retention,
+    pub server: ServerConfig,
+    pub autosave: Autosave,
+    pub state: StateOptions,
+    pub wal: WalOptions,
+    pub webhooks: Vec<Webhook>,
+    pub webhook_options: WebhookOptions,
+    pub hooks: Vec<Hook>,
+    pub hook_options: HookOptions,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Root {
+    pub path: PathBuf,
+    #[serde(default)]
+    pub filters: Filter,
+}
+
+impl Root {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path, filters: Filter::default() }
+    }
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Backend {
+    ///Keep the latest event per path in memory
+    #[default]
+    Memory,
+    ///Keep every event in an SQLite database, survives restarts and can be queried with `/events`
+    Sqlite,
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Storage {
+    pub backend: Backend,
+    ///Database file, `blazzy.db` next to the binary when not set
+    pub path: Option<PathBuf>,
+}
+
+impl Storage {
+    pub fn path(&self) -> PathBuf {
+        self.path.clone().unwrap_or_else(|| env::current_exe().unwrap().parent().unwrap().join("blazzy.db"))
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retention {
+    ///Memory: evict the least recently changed paths above this many entries. SQLite: keep this many events
+    pub max_entries: Option<usize>,
+    ///SQLite only: delete events older than this
+    pub max_age: Option<HumanDuration>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    pub listen: Vec<Listener>,
+    ///Permissions of unix socket files, in octal
+    #[serde(deserialize_with = "octal")]
+    pub socket_mode: u32,
+    pub tls: Option<Tls>,
+    pub tokens: Vec<Token>,
+    pub connection_type: Option<ConnectionType>,
+    pub websocket: WsOptions,
+    ///How long to wait for observers, clients and requests to finish on shutdown
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub shutdown_timeout: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen: vec![Listener::Tcp("127.0.0.1".to_string(), 8080)],
+            socket_mode: 0o660,
+            tls: None,
+            tokens: vec![],
+            connection_type: None,
+            websocket: WsOptions::default(),
+            shutdown_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Autosave {
+    pub enabled: bool,
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub delay: Duration,
+}
+
+impl Default for Autosave {
+    fn default() -> Self {
+        Self { enabled: false, delay: Duration::from_secs(5 * 60) }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let source = fs::read_to_string(path).map_err(|e| ConfigError::Io(path.to_path_buf(), e))?;
+        let is_yaml = path.extension().is_some_and(|ext| ext == "yaml" || ext == "yml");
+
+        if is_yaml {
+            serde_yaml::from_str(&source).map_err(|e| {
+                let location = e.location();
+                let message = e.to_string();
+                ConfigError::Parse {
+                    path: path.to_path_buf(),
+                    line: location.as_ref().map(|l| l.line()),
+                    column: location.as_ref().map(|l| l.column()),
+                    message: message.split(" at line ").next().unwrap_or_default().to_string(),
+                }
+            })
+        } else {
+            toml::from_str(&source).map_err(|e| {
+                let position = e.span().map(|span| {
+                    let before = &source[..span.start];
+                    let line = before.matches('\n').count() + 1;
+                    let column = span.start - before.rfind('\n').map(|i| i + 1).unwrap_or(0) + 1;
+                    (line, column)
+                });
+                ConfigError::Parse {
+                    path: path.to_path_buf(),
+                    line: position.map(|p| p.0),
+                    column: position.map(|p| p.1),
+                    message: e.message().to_string(),
+                }
+            })
+        }
+    }
+
+    ///Checks that don't belong to a single value, run after CLI overrides are applied
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.roots.is_empty() {
+            return Err(ConfigError::Invalid("no roots to observe, set `roots` or pass --path".to_string()));
+        }
+        for (name, size) in [
+            ("server.websocket.queue_size", self.server.websocket.queue_size),
+            ("server.websocket.batch_size", self.server.websocket.batch_size),
+        ] {
+            if size == 0 {
+                return Err(ConfigError::Invalid(format!("`{}` must be at least 1", name)));
+            }
+        }
+        // Used as timer periods, which can't be zero
+        for (name, interval) in [
+            ("autosave.delay", self.autosave.delay),
+            ("webhook_options.flush_interval", self.webhook_options.flush_interval),
+            ("server.websocket.flush_interval", self.server.websocket.flush_interval),
+        ] {
+            if interval.is_zero() {
+                return Err(ConfigError::Invalid(format!("`{}` must be longer than zero", name)));
+            }
+        }
+        match self.server.connection_type {
+            None | Some(ConnectionType::Unknown) => {
+                Err(ConfigError::Invalid("connection type is not set, set `server.connection_type` or pass -c".to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(PathBuf, io::Error),
+    Parse { path: PathBuf, line: Option<usize>, column: Option<usize>, message: String },
+    Invalid(String),
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+            ConfigError::Parse { path, line: Some(line), column: Some(column), message } => {
+                write!(f, "{}:{}:{}: {}", path.display(), line, column, message)
+            }
+            ConfigError::Parse { path, message, .. } => write!(f, "{}: {}", path.display(), message),
+            ConfigError::Invalid(message) => write!(f, "{}", message),
+        }
+    }
+}
+
+pub(crate) fn octal<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let mode = String::deserialize(deserializer)?;
+    u32::from_str_radix(&mode, 8).map_err(|_| serde::de::Error::custom(format!("`{}` is not an octal mode", mode)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+    use clap::Parser;
+    use crate::cli::CLI;
+    use crate::config::{Config, ConfigError};
+
+    fn load(name: &str, source: &str) -> Result<Config, ConfigError> {
+        let path = std::env::temp_dir().join(format!("blazzy-{}-{}", std::process::id(), name));
+        std::fs::File::create(&path).unwrap().write_all(source.as_bytes()).unwrap();
+        let config = Config::load(&path);
+        std::fs::remove_file(path).unwrap();
+        config
+    }
+
+    #[test]
+    fn load_toml_test() {
+        let config = load("ok.toml", r#"
+logs = true
+
+[[roots]]
+path = '/srv'
+filters = { exclude = ["**/*.tmp"] }
+
+[server]
+listen = ["0.0.0.0:9000", "unix:/run/blazzy.sock"]
+connection_type = "websocket"
+socket_mode = "600"
+
+[[hooks]]
+glob = "**/*.rs"
+run = "cargo build"
+"#).unwrap();
+        assert!(config.logs);
+        assert_eq!(config.roots[0].path, PathBuf::from("/srv"));
+        assert_eq!(config.server.listen.len(), 2);
+        assert_eq!(config.server.socket_mode, 0o600);
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn zero_interval_test() {
+        let config = load("zero.toml", "[[roots]]\npath = '/srv'\n\n[server]\nconnection_type = \"rest\"\n\n[autosave]\ndelay = \"0s\"\n").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(message)) if message.contains("autosave.delay")));
+
+        let config = load("zero-batch.toml", "[[roots]]\npath = '/srv'\n\n[server]\nconnection_type = \"websocket\"\nwebsocket = { batch_size = 0 }\n").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(message)) if message.contains("batch_size")));
+
+        let config = load("zero-queue.toml", "[[roots]]\npath = '/srv'\n\n[server]\nconnection_type = \"websocket\"\nwebsocket = { queue_size = 0 }\n").unwrap();
+        assert!(matches!(config.validate(), Err(ConfigError::Invalid(message)) if message.contains("queue_size")));
+        let cli = CLI::parse_from(["blazzy", "-p", "/srv", "-c", "w", "--ws-queue-size", "0"]);
+        assert!(matches!(cli.load_config(), Err(ConfigError::Invalid(message)) if message.contains("queue_size")));
+
+        let config = load("zero.yaml", "roots:\n  - path: /srv\nserver:\n  connection_type: rest\nwebhook_options:\n  flush_interval: 0ms\n").unwrap();
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn error_line_test() {
+        let err = load("bad.toml", "logs = true\n\n[server]\nlisten = 8080\n").unwrap_err();
+        match err {
+            ConfigError::Parse { line, column, .. } => assert_eq!((line, column), (Some(4), Some(10))),
+            _ => panic!("expected parse error, got {}", err),
+        }
+
+        let err = load("bad.yaml", "logs: true\nroots:\n  - path: /srv\n    typo: 1\n").unwrap_err();
+        match err {
+            ConfigError::Parse { line, .. } => assert_eq!(line, Some(4)),
+            _ => panic!("expected parse error, got {}", err),
+        }
+    }
+}
@@ -0,0 +1,209 @@
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+use std::time::Duration;
+use serde::{Deserialize, Deserializer};
+
+static LEGACY_METRICS: [(&str, u64); 7] = [
+    ("nsec", 1),
+    ("micsec", 1_000),
+    ("msec", 1_000_000),
+    ("sec", 1_000_000_000),
+    ("min", 60 * 1_000_000_000),
+    ("hour", 60 * 60 * 1_000_000_000),
+    ("day", 24 * 60 * 60 * 1_000_000_000),
+];
+
+static UNITS: [(&str, u64); 9] = [
+    ("ns", 1),
+    ("us", 1_000),
+    ("µs", 1_000),
+    ("ms", 1_000_000),
+    ("s", 1_000_000_000),
+    ("m", 60 * 1_000_000_000),
+    ("h", 60 * 60 * 1_000_000_000),
+    ("d", 24 * 60 * 60 * 1_000_000_000),
+    ("w", 7 * 24 * 60 * 60 * 1_000_000_000),
+];
+
+const TOO_LARGE: &str = "duration is too large";
+
+///Duration written as `90s`, `5m`, `1h30m`, `250ms`, ISO 8601 (`PT1H30M`) or the older `5:min`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HumanDuration(pub Duration);
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let nanos = if s.is_empty() {
+            return Err("empty duration".to_string());
+        } else if let Some(iso) = s.strip_prefix('P') {
+            parse_iso8601(iso)
+        } else if let Some((value, metric)) = s.split_once(':') {
+            parse_legacy(value, metric)
+        } else {
+            parse_units(s)
+        }.map_err(|e| format!("invalid duration `{}`: {}", s, e))?;
+
+        let nanos = u64::try_from(nanos).map_err(|_| format!("invalid duration `{}`: {}", s, TOO_LARGE))?;
+        Ok(HumanDuration(Duration::from_nanos(nanos)))
+    }
+}
+
+impl TryFrom<String> for HumanDuration {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl<'de> Deserialize<'de> for HumanDuration {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl Display for HumanDuration {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let mut nanos = self.0.as_nanos();
+        if nanos == 0 {
+            return write!(f, "0s");
+        }
+        for (unit, size) in [("d", UNITS[7].1), ("h", UNITS[6].1), ("m", UNITS[5].1), ("s", UNITS[4].1), ("ms", UNITS[3].1), ("us", UNITS[1].1), ("ns", 1)] {
+            let size = size as u128;
+            if nanos >= size {
+                write!(f, "{}{}", nanos / size, unit)?;
+                nanos %= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(duration: HumanDuration) -> Self {
+        duration.0
+    }
+}
+
+///For `#[serde(deserialize_with)]` on plain `Duration` fields
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+    Ok(HumanDuration::deserialize(deserializer)?.0)
+}
+
+fn parse_legacy(value: &str, metric: &str) -> Result<u128, String> {
+    let size = LEGACY_METRICS.iter().find(|(name, _)| *name == metric)
+        .map(|(_, size)| *size)
+        .ok_or_else(|| format!("unknown unit `{}`", metric))?;
+    let value = value.parse::<u64>().map_err(|_| format!("`{}` is not a number", value))?;
+    u128::from(value).checked_mul(u128::from(size)).ok_or_else(|| TOO_LARGE.to_string())
+}
+
+///`1h30m`, `90s`, `1.5h`
+fn parse_units(s: &str) -> Result<u128, String> {
+    let mut total = 0u128;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let number_len = rest.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(rest.len());
+        let (number, tail) = rest.split_at(number_len);
+        if number.is_empty() {
+            return Err(format!("expected a number before `{}`", tail));
+        }
+        let unit_len = tail.find(|c: char| c.is_ascii_digit() || c == '.').unwrap_or(tail.len());
+        let (unit, tail) = tail.split_at(unit_len);
+        if unit.is_empty() {
+            return Err(format!("missing unit after `{}`, e.g. {}s or {}m", number, number, number));
+        }
+        let size = UNITS.iter().find(|(name, _)| *name == unit)
+            .map(|(_, size)| *size)
+            .ok_or_else(|| format!("unknown unit `{}`, expected one of ns, us, ms, s, m, h, d, w", unit))?;
+        total = total.checked_add(scale(number, size)?).ok_or_else(|| TOO_LARGE.to_string())?;
+        rest = tail;
+    }
+    Ok(total)
+}
+
+///Everything after the leading `P`: `[nW][nD][T[nH][nM][nS]]`, years and months have no fixed length
+fn parse_iso8601(s: &str) -> Result<u128, String> {
+    let (date, time) = match s.split_once('T') {
+        Some((date, time)) => (date, Some(time)),
+        None => (s, None),
+    };
+    if date.is_empty() && time.is_none_or(str::is_empty) {
+        return Err("no components".to_string());
+    }
+
+    let mut total = 0u128;
+    for (part, units) in [(date, &[('W', UNITS[8].1), ('D', UNITS[7].1)][..]), (time.unwrap_or_default(), &[('H', UNITS[6].1), ('M', UNITS[5].1), ('S', UNITS[4].1)][..])] {
+        let mut rest = part;
+        let mut allowed = units;
+        while !rest.is_empty() {
+            let designator_at = rest.find(|c: char| c.is_ascii_alphabetic())
+                .ok_or_else(|| format!("missing designator after `{}`", rest))?;
+            let (number, tail) = rest.split_at(designator_at);
+            let designator = tail.chars().next().unwrap();
+            let position = allowed.iter().position(|(d, _)| *d == designator)
+                .ok_or_else(|| format!("unexpected `{}`", designator))?;
+            total = total.checked_add(scale(number, allowed[position].1)?).ok_or_else(|| TOO_LARGE.to_string())?;
+            allowed = &allowed[position + 1..];
+            rest = &tail[1..];
+        }
+    }
+    Ok(total)
+}
+
+fn scale(number: &str, size: u64) -> Result<u128, String> {
+    let (whole, fraction) = number.split_once('.').unwrap_or((number, ""));
+    let whole = if whole.is_empty() { 0 } else { whole.parse::<u128>().map_err(|_| format!("`{}` is not a number", number))? };
+    let size = u128::from(size);
+    let mut nanos = whole.checked_mul(size).ok_or_else(|| TOO_LARGE.to_string())?;
+    if !fraction.is_empty() {
+        // At most 18 digits, so neither the parse nor the product below can overflow
+        let digits = fraction.len().min(18);
+        let fraction = fraction[..digits].parse::<u128>().map_err(|_| format!("`{}` is not a number", number))?;
+        nanos = nanos.checked_add(fraction * size / 10u128.pow(digits as u32)).ok_or_else(|| TOO_LARGE.to_string())?;
+    }
+    Ok(nanos)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use crate::duration::HumanDuration;
+
+    fn parse(s: &str) -> Duration {
+        s.parse::<HumanDuration>().unwrap().0
+    }
+
+    #[test]
+    fn parse_test() {
+        assert_eq!(parse("90s"), Duration::from_secs(90));
+        assert_eq!(parse("5m"), Duration::from_secs(300));
+        assert_eq!(parse("1h30m"), Duration::from_secs(5400));
+        assert_eq!(parse("250ms"), Duration::from_millis(250));
+        assert_eq!(parse("1.5h"), Duration::from_secs(5400));
+        assert_eq!(parse("PT1H30M"), Duration::from_secs(5400));
+        assert_eq!(parse("P1DT0.5S"), Duration::from_millis(86_400_500));
+        assert_eq!(parse("5:min"), Duration::from_secs(300));
+        assert_eq!(parse("10:msec"), Duration::from_millis(10));
+    }
+
+    #[test]
+    fn reject_test() {
+        for bad in ["", "10", "10min", "5:minutes", "m5", "P1Y", "PT", "PT5M3H", "1h 30m"] {
+            assert!(bad.parse::<HumanDuration>().is_err(), "{} should be rejected", bad);
+        }
+        for huge in ["99999999999999999999999999999w", "340282366920938463463374607431768211455ns1ns", "P99999999999999999999999999999W", "18446744073709551615:day", "584555d"] {
+            assert!(huge.parse::<HumanDuration>().unwrap_err().contains("too large"), "{} should be rejected", huge);
+        }
+    }
+
+    #[test]
+    fn display_test() {
+        assert_eq!(HumanDuration(Duration::from_secs(5400)).to_string(), "1h30m");
+        assert_eq!(HumanDuration(Duration::from_millis(10)).to_string(), "10ms");
+        assert_eq!(HumanDuration(Duration::ZERO).to_string(), "0s");
+    }
+}
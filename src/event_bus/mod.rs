@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+use lazy_static::lazy_static;
+use tokio::sync::broadcast::{self, Receiver, Sender};
+use crate::observer::Data;
+use crate::watches::WatchError;
+
+const BUS_CAPACITY: usize = 4096;
+
+lazy_static!{
+    pub static ref SharedEventBus: EventBus = EventBus::init(BUS_CAPACITY);
+}
+
+///Fan-out of observed events to every stream consumer (websocket clients, etc.)
+pub struct EventBus {
+    tx: Sender<(PathBuf, Data)>,
+    ///Observer failures, kept apart from events so they are never cached or sent to hooks
+    errors: Sender<WatchError>,
+}
+
+impl EventBus {
+    pub fn init(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        let (errors, _) = broadcast::channel(capacity);
+        Self { tx, errors }
+    }
+
+    pub fn publish(&self, path_buf: PathBuf, data: Data) {
+        // No subscribers is not an error, the event is still cached
+        let _ = self.tx.send((path_buf, data));
+    }
+
+    pub fn subscribe(&self) -> Receiver<(PathBuf, Data)> {
+        self.tx.subscribe()
+    }
+
+    pub fn publish_error(&self, error: WatchError) {
+        let _ = self.errors.send(error);
+    }
+
+    pub fn subscribe_errors(&self) -> Receiver<WatchError> {
+        self.errors.subscribe()
+    }
+}
@@ -0,0 +1,397 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use clap::{Args, ValueEnum};
+use futures::{stream, Stream};
+use parquet::basic::Compression;
+use parquet::data_type::{BoolType, ByteArray, ByteArrayType, Int64Type};
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::{SerializedFileWriter, SerializedRowGroupWriter};
+use parquet::schema::parser::parse_message_type;
+use serde::{Deserialize, Serialize};
+use crate::async_cacher::AsyncCacher;
+use crate::auth::Scope;
+use crate::config::{Backend, Config};
+use crate::observer::{Action, Data};
+use crate::state::StateFile;
+use crate::storage::{parse_time, Event, HistoryQuery, SqliteStore, Store};
+use crate::wal::Wal;
+
+///Events fetched from the store at once
+pub const PAGE_SIZE: usize = 10_000;
+
+///Output columns, in order
+const COLUMNS: [(&str, Column); 13] = [
+    ("id", Column::Int(|row| row.id)),
+    ("time", Column::Text(|row| row.time.as_deref())),
+    ("path", Column::Text(|row| Some(&row.path))),
+    ("action", Column::Text(|row| Some(&row.action))),
+    ("file_type", Column::Text(|row| row.file_type.as_deref())),
+    ("is_dir", Column::Bool(|row| row.is_dir)),
+    ("is_file", Column::Bool(|row| row.is_file)),
+    ("is_symlink", Column::Bool(|row| row.is_symlink)),
+    ("len_in_bytes", Column::Int(|row| row.len_in_bytes.map(|len| len as i64))),
+    ("permissions", Column::Text(|row| row.permissions.as_deref())),
+    ("modified", Column::Text(|row| row.modified.as_deref())),
+    ("accessed", Column::Text(|row| row.accessed.as_deref())),
+    ("created", Column::Text(|row| row.created.as_deref())),
+];
+
+enum Column {
+    Int(fn(&Row) -> Option<i64>),
+    Bool(fn(&Row) -> Option<bool>),
+    Text(fn(&Row) -> Option<&str>),
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ExportFormat {
+    ///One JSON object per line
+    #[default]
+    Ndjson,
+    Csv,
+    Parquet,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "application/x-ndjson",
+            ExportFormat::Csv => "text/csv",
+            ExportFormat::Parquet => "application/vnd.apache.parquet",
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ExportFormat::Ndjson => "ndjson",
+            ExportFormat::Csv => "csv",
+            ExportFormat::Parquet => "parquet",
+        }
+    }
+}
+
+///Which events to export, shared by `GET /export` and `blazzy export`
+#[derive(Args, Deserialize, Debug, Clone, Default)]
+#[serde(default)]
+pub struct ExportFilter {
+    ///Only events under this path
+    #[arg(long)]
+    pub path: Option<PathBuf>,
+    ///Only events with this action
+    #[arg(long)]
+    pub action: Option<Action>,
+    ///RFC 3339 time or a duration before now like 1h, history only
+    #[arg(long)]
+    pub since: Option<String>,
+    ///RFC 3339 time or a duration before now, history only
+    #[arg(long)]
+    pub until: Option<String>,
+}
+
+impl ExportFilter {
+    ///First page of the history matching the filter
+    pub fn query(&self, scope: Scope) -> Result<HistoryQuery, String> {
+        Ok(HistoryQuery {
+            path: self.path.clone(),
+            action: self.action,
+            since: self.since.as_deref().map(parse_time).transpose()?,
+            until: self.until.as_deref().map(parse_time).transpose()?,
+            before: None,
+            limit: PAGE_SIZE,
+            scope,
+        })
+    }
+
+    ///For stores without history, times are not known so only path and action apply
+    pub fn allows(&self, path: &Path, data: &Data) -> bool {
+        self.path.as_ref().is_none_or(|prefix| path.starts_with(prefix))
+            && self.action.is_none_or(|action| action == data.action())
+    }
+}
+
+///One event with the metadata flattened into columns
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Row {
+    pub id: Option<i64>,
+    pub time: Option<String>,
+    pub path: String,
+    pub action: String,
+    pub file_type: Option<String>,
+    pub is_dir: Option<bool>,
+    pub is_file: Option<bool>,
+    pub is_symlink: Option<bool>,
+    pub len_in_bytes: Option<u64>,
+    pub permissions: Option<String>,
+    pub modified: Option<String>,
+    pub accessed: Option<String>,
+    pub created: Option<String>,
+}
+
+impl Row {
+    pub fn new(path: &Path, data: &Data) -> Self {
+        let metadata = data.metadata();
+        Self {
+            id: None,
+            time: None,
+            path: path.to_string_lossy().to_string(),
+            action: String::from(data.action()),
+            file_type: metadata.map(|m| m.file_type.clone()),
+            is_dir: metadata.map(|m| m.is_dir),
+            is_file: metadata.map(|m| m.is_file),
+            is_symlink: metadata.map(|m| m.is_symlink),
+            len_in_bytes: metadata.map(|m| m.len_in_bytes),
+            permissions: metadata.map(|m| m.permissions.clone()),
+            modified: metadata.map(|m| m.modified.clone()),
+            accessed: metadata.map(|m| m.accessed.clone()),
+            created: metadata.map(|m| m.created.clone()),
+        }
+    }
+}
+
+impl From<&Event> for Row {
+    fn from(event: &Event) -> Self {
+        Self {
+            id: Some(event.id),
+            time: Some(event.time.clone()),
+            ..Row::new(&event.path, &event.data)
+        }
+    }
+}
+
+///Turns pages of rows into output chunks, `finish` returns what is left (the footer for Parquet).
+///A Parquet page leaves as a row group once it is written, so only the footer metadata grows with the export
+pub struct Encoder {
+    format: ExportFormat,
+    wrote_header: bool,
+    parquet: Option<(SerializedFileWriter<Drain>, Drain)>,
+}
+
+///Sink of the Parquet writer, emptied after every row group
+#[derive(Clone, Default)]
+struct Drain(Arc<Mutex<Vec<u8>>>);
+
+impl Drain {
+    fn take(&self) -> Vec<u8> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Write for Drain {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Encoder {
+    pub fn new(format: ExportFormat) -> io::Result<Self> {
+        let parquet = match format {
+            ExportFormat::Parquet => {
+                let schema = Arc::new(parse_message_type(&parquet_schema()).map_err(other)?);
+                let properties = Arc::new(WriterProperties::builder().set_compression(Compression::SNAPPY).build());
+                let drain = Drain::default();
+                Some((SerializedFileWriter::new(drain.clone(), schema, properties).map_err(other)?, drain))
+            }
+            _ => None,
+        };
+        Ok(Self { format, wrote_header: false, parquet })
+    }
+
+    pub fn encode(&mut self, rows: &[Row]) -> io::Result<Vec<u8>> {
+        match self.format {
+            ExportFormat::Ndjson => {
+                let mut out = vec![];
+                for row in rows {
+                    serde_json::to_writer(&mut out, row)?;
+                    out.push(b'\n');
+                }
+                Ok(out)
+            }
+            ExportFormat::Csv => {
+                let mut writer = csv::WriterBuilder::new().has_headers(!self.wrote_header).from_writer(vec![]);
+                for row in rows {
+                    writer.serialize(row).map_err(other)?;
+                    self.wrote_header = true;
+                }
+                writer.into_inner().map_err(|e| other(e.to_string()))
+            }
+            ExportFormat::Parquet => {
+                let (writer, drain) = self.parquet.as_mut().unwrap();
+                if !rows.is_empty() {
+                    let mut row_group = writer.next_row_group().map_err(other)?;
+                    write_row_group(&mut row_group, rows).map_err(other)?;
+                    row_group.close().map_err(other)?;
+                }
+                Ok(drain.take())
+            }
+        }
+    }
+
+    pub fn finish(self) -> io::Result<Vec<u8>> {
+        match self.format {
+            ExportFormat::Csv if !self.wrote_header => {
+                let columns = COLUMNS.iter().map(|(name, _)| *name).collect::<Vec<_>>();
+                Ok(format!("{}\n", columns.join(",")).into_bytes())
+            }
+            ExportFormat::Parquet => {
+                let (writer, drain) = self.parquet.unwrap();
+                writer.close().map_err(other)?;
+                Ok(drain.take())
+            }
+            _ => Ok(vec![]),
+        }
+    }
+}
+
+///Chunks of the export as it is read from the cache, history is fetched page by page
+pub fn stream(cacher: Arc<AsyncCacher>, format: ExportFormat, filter: ExportFilter, scope: Scope) -> Result<impl Stream<Item = io::Result<Vec<u8>>>, String> {
+    let query = filter.query(scope.clone())?;
+    let encoder = Encoder::new(format).map_err(|e| e.to_string())?;
+    Ok(stream::try_unfold(Some((query, encoder)), move |state| {
+        let cacher = cacher.clone();
+        let filter = filter.clone();
+        let scope = scope.clone();
+        async move {
+            let Some((mut query, mut encoder)) = state else { return Ok(None) };
+            let rows = if cacher.keeps_history().await.map_err(other)? {
+                let events = cacher.history(query.clone()).await.map_err(other)?;
+                if events.len() == PAGE_SIZE {
+                    query.before = events.last().map(|event| event.id);
+                    let chunk = encoder.encode(&events.iter().map(Row::from).collect::<Vec<_>>())?;
+                    return Ok(Some((chunk, Some((query, encoder)))));
+                }
+                events.iter().map(Row::from).collect::<Vec<_>>()
+            } else {
+                // No history, export the latest event of every path
+                cacher.get().await.map_err(other)?.iter()
+                    .filter(|(path, data)| scope.allows(path) && filter.allows(path, data))
+                    .map(|(path, data)| Row::new(path, data))
+                    .collect()
+            };
+            let mut chunk = encoder.encode(&rows)?;
+            chunk.extend(encoder.finish()?);
+            Ok(Some((chunk, None)))
+        }
+    }))
+}
+
+///`blazzy export`: reads the database, or the state file and WAL, without a running server
+pub fn export_offline(config: &Config, format: ExportFormat, filter: &ExportFilter, out: &mut dyn Write) -> io::Result<usize> {
+    let mut encoder = Encoder::new(format)?;
+    let mut count = 0;
+    match config.storage.backend {
+        Backend::Sqlite => {
+            let mut store = SqliteStore::open(&config.storage.path()).map_err(other)?;
+            let mut query = filter.query(Scope::full()).map_err(other)?;
+            loop {
+                let events = store.history(&query).map_err(other)?;
+                count += events.len();
+                out.write_all(&encoder.encode(&events.iter().map(Row::from).collect::<Vec<_>>())?)?;
+                if events.len() < PAGE_SIZE {
+                    break;
+                }
+                query.before = events.last().map(|event| event.id);
+            }
+        }
+        Backend::Memory => {
+            let mut entries = StateFile::new(config.state.clone()).load()?;
+            let wal = config.wal.path(&config.state);
+            if config.wal.enabled && wal.exists() {
+                entries.extend(Wal::open(wal, false)?.replay()?);
+            }
+            let latest = entries.into_iter().collect::<HashMap<_, _>>();
+            let rows = latest.iter()
+                .filter(|(path, data)| filter.allows(path, data))
+                .map(|(path, data)| Row::new(path, data))
+                .collect::<Vec<_>>();
+            count = rows.len();
+            out.write_all(&encoder.encode(&rows)?)?;
+        }
+    }
+    out.write_all(&encoder.finish()?)?;
+    out.flush()?;
+    Ok(count)
+}
+
+fn parquet_schema() -> String {
+    let fields = COLUMNS.iter().map(|(name, column)| match column {
+        Column::Int(_) => format!("OPTIONAL INT64 {};", name),
+        Column::Bool(_) => format!("OPTIONAL BOOLEAN {};", name),
+        Column::Text(_) => format!("OPTIONAL BYTE_ARRAY {} (UTF8);", name),
+    }).collect::<Vec<_>>();
+    format!("message event {{ {} }}", fields.join(" "))
+}
+
+fn write_row_group(row_group: &mut SerializedRowGroupWriter<'_, Drain>, rows: &[Row]) -> parquet::errors::Result<()> {
+    for (_, column) in &COLUMNS {
+        let mut writer = row_group.next_column()?.unwrap();
+        match column {
+            Column::Int(get) => {
+                let (values, levels) = levels(rows, get);
+                writer.typed::<Int64Type>().write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Bool(get) => {
+                let (values, levels) = levels(rows, get);
+                writer.typed::<BoolType>().write_batch(&values, Some(&levels), None)?;
+            }
+            Column::Text(get) => {
+                let (values, levels) = levels(rows, |row| get(row).map(|s| ByteArray::from(s.as_bytes().to_vec())));
+                writer.typed::<ByteArrayType>().write_batch(&values, Some(&levels), None)?;
+            }
+        }
+        writer.close()?;
+    }
+    Ok(())
+}
+
+///Present values and the definition level of every row, 0 for nulls
+fn levels<T>(rows: &[Row], get: impl Fn(&Row) -> Option<T>) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::with_capacity(rows.len());
+    let mut levels = Vec::with_capacity(rows.len());
+    for row in rows {
+        match get(row) {
+            Some(value) => {
+                values.push(value);
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+    (values, levels)
+}
+
+fn other<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::other(e)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::export::{Encoder, ExportFormat, Row};
+    use crate::observer::{Action, Data};
+
+    #[test]
+    fn encode_test() {
+        let rows = vec![Row::new(&PathBuf::from("/srv/a,b"), &Data::new(Action::Created, None))];
+
+        let mut csv = Encoder::new(ExportFormat::Csv).unwrap();
+        let out = String::from_utf8(csv.encode(&rows).unwrap()).unwrap();
+        assert_eq!(out.lines().next().unwrap(), "id,time,path,action,file_type,is_dir,is_file,is_symlink,len_in_bytes,permissions,modified,accessed,created");
+        assert_eq!(out.lines().nth(1).unwrap(), ",,\"/srv/a,b\",Created,,,,,,,,,");
+        assert!(csv.finish().unwrap().is_empty());
+
+        // The row group is out before the footer
+        let mut parquet = Encoder::new(ExportFormat::Parquet).unwrap();
+        let mut file = parquet.encode(&rows).unwrap();
+        assert_eq!(&file[..4], b"PAR1");
+        assert!(parquet.encode(&rows).unwrap().len() > 4);
+        file.extend(parquet.finish().unwrap());
+        assert_eq!(&file[file.len() - 4..], b"PAR1");
+    }
+}
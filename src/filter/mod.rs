@@ -0,0 +1,92 @@
+use std::path::Path;
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use serde::Deserialize;
+pub use blazzy_client::models::Patterns;
+
+///Include/exclude globs deciding which observed paths are kept, an empty include list keeps everything
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "Patterns")]
+pub struct Filter {
+    ///Every set must match, one per merged filter that has includes
+    includes: Vec<GlobSet>,
+    ///Any set matching drops the path
+    excludes: Vec<GlobSet>,
+}
+
+impl Filter {
+    pub fn new(patterns: Patterns) -> Result<Self, globset::Error> {
+        Ok(Self {
+            includes: Self::build(&patterns.include)?.into_iter().collect(),
+            excludes: Self::build(&patterns.exclude)?.into_iter().collect(),
+        })
+    }
+
+    pub fn allows(&self, path: &Path) -> bool {
+        self.includes.iter().all(|include| include.is_match(path)) && !self.excludes.iter().any(|exclude| exclude.is_match(path))
+    }
+
+    ///Filter keeping only paths both keep: includes of each must match, excludes of either drop the path
+    pub fn merge(&self, other: &Filter) -> Filter {
+        Filter {
+            includes: self.includes.iter().chain(&other.includes).cloned().collect(),
+            excludes: self.excludes.iter().chain(&other.excludes).cloned().collect(),
+        }
+    }
+
+    ///`None` for no globs
+    fn build(globs: &[String]) -> Result<Option<GlobSet>, globset::Error> {
+        if globs.is_empty() {
+            return Ok(None);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for glob in globs {
+            builder.add(Glob::new(glob)?);
+        }
+        builder.build().map(Some)
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Filter::new(Patterns::default()).unwrap()
+    }
+}
+
+impl TryFrom<Patterns> for Filter {
+    type Error = globset::Error;
+
+    fn try_from(patterns: Patterns) -> Result<Self, Self::Error> {
+        Filter::new(patterns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use crate::filter::{Filter, Patterns};
+
+    #[test]
+    fn include_exclude_test() {
+        let filter = Filter::new(Patterns {
+            include: vec!["**/*.rs".to_string()],
+            exclude: vec!["**/target/**".to_string()],
+        }).unwrap();
+        assert!(filter.allows(Path::new("src/main.rs")));
+        assert!(!filter.allows(Path::new("target/debug/build.rs")));
+        assert!(!filter.allows(Path::new("README.md")));
+        assert!(Filter::default().allows(Path::new("README.md")));
+    }
+
+    #[test]
+    fn merge_test() {
+        let global = Filter::new(Patterns { include: vec!["src/**".to_string()], exclude: vec!["**/*.tmp".to_string()] }).unwrap();
+        let root = Filter::new(Patterns { include: vec!["**/*.rs".to_string()], exclude: vec!["**/gen/**".to_string()] }).unwrap();
+        let filter = global.merge(&root);
+        assert!(filter.allows(Path::new("src/main.rs")));
+        assert!(!filter.allows(Path::new("src/README.md")));
+        assert!(!filter.allows(Path::new("tests/main.rs")));
+        assert!(!filter.allows(Path::new("src/gen/main.rs")));
+        assert!(!filter.allows(Path::new("src/main.tmp")));
+        assert!(global.merge(&Filter::default()).allows(Path::new("src/README.md")));
+    }
+}
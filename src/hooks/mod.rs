@@ -0,0 +1,311 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use chrono::Local;
+use globset::{Glob, GlobMatcher};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, warn};
+use crate::event_bus::SharedEventBus;
+use crate::metrics;
+use crate::observer::{Action, Data};
+
+const HISTORY_SIZE: usize = 100;
+const OUTPUT_LIMIT: usize = 4096;
+
+lazy_static!{
+    pub static ref SharedHookRuns: Arc<Mutex<VecDeque<HookRun>>> = Arc::new(Mutex::new(VecDeque::with_capacity(HISTORY_SIZE)));
+}
+
+///Hook given on the command line as `GLOB[;action=ACTION,...];run=COMMAND [ARGS...]`, quotes group words with spaces.
+///The path and action are appended to the arguments and exported as `BLAZZY_PATH`/`BLAZZY_ACTION`
+#[derive(Deserialize, Debug, Clone)]
+#[serde(try_from = "HookConfig")]
+pub struct Hook {
+    pattern: String,
+    matcher: GlobMatcher,
+    actions: Vec<Action>,
+    command: Vec<String>,
+}
+
+impl Hook {
+    fn matches(&self, path_buf: &Path, data: &Data) -> bool {
+        self.matcher.is_match(path_buf) && (self.actions.is_empty() || self.actions.contains(&data.action()))
+    }
+}
+
+impl FromStr for Hook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let mut config = HookConfig {
+            glob: parts.next().unwrap_or_default().to_string(),
+            actions: vec![],
+            run: Run::Line(String::new()),
+        };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("action", list)) => config.actions = list.split(',').map(Action::from_str).collect::<Result<_, _>>()?,
+                Some(("run", run)) => config.run = Run::Line(run.to_string()),
+                _ => return Err(format!("unknown hook option: {}", part)),
+            }
+        }
+        Hook::try_from(config)
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HookConfig {
+    glob: String,
+    #[serde(default)]
+    actions: Vec<Action>,
+    run: Run,
+}
+
+///A command line, or the program and its arguments as a list
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Run {
+    Line(String),
+    Argv(Vec<String>),
+}
+
+impl TryFrom<HookConfig> for Hook {
+    type Error = String;
+
+    fn try_from(config: HookConfig) -> Result<Self, Self::Error> {
+        let matcher = Glob::new(&config.glob).map_err(|e| e.to_string())?.compile_matcher();
+        let command = match config.run {
+            Run::Line(line) => split_command(&line)?,
+            Run::Argv(argv) => argv,
+        };
+        if command.is_empty() {
+            return Err("hook needs a command, e.g. `**/*.rs;run=cargo build`".to_string());
+        }
+        Ok(Self { pattern: config.glob, matcher, actions: config.actions, command })
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct HookOptions {
+    ///How long a path must stay quiet before its hooks run
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub debounce: Duration,
+    ///Max commands running at once
+    pub concurrency: usize,
+    ///Kill commands running longer than this
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub timeout: Duration,
+}
+
+impl Default for HookOptions {
+    fn default() -> Self {
+        Self {
+            debounce: Duration::from_millis(500),
+            concurrency: 4,
+            timeout: Duration::from_secs(60),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "snake_case")]
+pub enum HookStatus {
+    Succeeded,
+    Failed,
+    TimedOut,
+    SpawnFailed,
+}
+
+///Outcome of one hook invocation, kept for `GET /hooks/runs`
+#[derive(Serialize, Debug, Clone)]
+pub struct HookRun {
+    hook: String,
+    path: PathBuf,
+    action: Action,
+    started: String,
+    duration_ms: u128,
+    status: HookStatus,
+    exit_code: Option<i32>,
+    stdout: String,
+    stderr: String,
+}
+
+impl HookRun {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+pub struct HookRunner {
+    hooks: Vec<Arc<Hook>>,
+    options: HookOptions,
+    permits: Arc<Semaphore>,
+}
+
+impl HookRunner {
+    pub fn init(hooks: Vec<Hook>, options: HookOptions) -> Self {
+        Self {
+            hooks: hooks.into_iter().map(Arc::new).collect(),
+            permits: Arc::new(Semaphore::new(options.concurrency.max(1))),
+            options,
+        }
+    }
+
+    ///Runs a hook once a path has been quiet for the debounce period
+    pub async fn run(self) {
+        let mut events = SharedEventBus.subscribe();
+        let mut pending: HashMap<(usize, PathBuf), (Action, Instant)> = HashMap::new();
+        let mut tick = tokio::time::interval(self.options.debounce.clamp(Duration::from_millis(10), Duration::from_millis(100)));
+
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok((key, value)) => {
+                        for (i, hook) in self.hooks.iter().enumerate() {
+                            if hook.matches(&key, &value) {
+                                pending.insert((i, key.clone()), (value.action(), Instant::now()));
+                            }
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(missed = n, "Hooks fell behind");
+                        metrics::DROPPED.with_label_values(&["hooks"]).inc_by(n);
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tick.tick() => {
+                    let ready = pending.iter()
+                        .filter(|(_, (_, last))| last.elapsed() >= self.options.debounce)
+                        .map(|(key, _)| key.clone())
+                        .collect::<Vec<_>>();
+                    for key in ready {
+                        let (action, _) = pending.remove(&key).unwrap();
+                        let (i, path) = key;
+                        tokio::task::spawn(Self::execute(self.hooks[i].clone(), path, action, self.permits.clone(), self.options.timeout));
+                    }
+                }
+            }
+        }
+    }
+
+    async fn execute(hook: Arc<Hook>, path: PathBuf, action: Action, permits: Arc<Semaphore>, timeout: Duration) {
+        let _permit = permits.acquire_owned().await.unwrap();
+        let started = Local::now();
+        let clock = Instant::now();
+
+        let child = Command::new(&hook.command[0])
+            .args(&hook.command[1..])
+            .arg(&path)
+            .arg(format!("{:?}", action))
+            .env("BLAZZY_PATH", &path)
+            .env("BLAZZY_ACTION", format!("{:?}", action))
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+
+        let (status, exit_code, stdout, stderr) = match child {
+            Err(e) => (HookStatus::SpawnFailed, None, String::new(), e.to_string()),
+            Ok(child) => match tokio::time::timeout(timeout, child.wait_with_output()).await {
+                Err(_) => (HookStatus::TimedOut, None, String::new(), String::new()),
+                Ok(Err(e)) => (HookStatus::Failed, None, String::new(), e.to_string()),
+                Ok(Ok(output)) => (
+                    if output.status.success() { HookStatus::Succeeded } else { HookStatus::Failed },
+                    output.status.code(),
+                    tail(&output.stdout),
+                    tail(&output.stderr),
+                ),
+            },
+        };
+
+        let run = HookRun {
+            hook: hook.pattern.clone(),
+            path,
+            action,
+            started: started.to_rfc3339(),
+            duration_ms: clock.elapsed().as_millis(),
+            status,
+            exit_code,
+            stdout,
+            stderr,
+        };
+        match run.status {
+            HookStatus::Succeeded => debug!(hook = %run.hook, path = %run.path.display(), duration_ms = run.duration_ms, "Hook succeeded"),
+            _ => warn!(hook = %run.hook, path = %run.path.display(), status = ?run.status, exit_code = ?run.exit_code, "Hook failed"),
+        }
+        let mut runs = SharedHookRuns.lock().unwrap();
+        if runs.len() == HISTORY_SIZE {
+            runs.pop_front();
+        }
+        runs.push_back(run);
+    }
+}
+
+fn tail(output: &[u8]) -> String {
+    let start = output.len().saturating_sub(OUTPUT_LIMIT);
+    String::from_utf8_lossy(&output[start..]).to_string()
+}
+
+///Splits at whitespace outside of single or double quotes. Backslashes are kept as they are, so Windows paths
+///need no escaping
+fn split_command(line: &str) -> Result<Vec<String>, String> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut quote = None;
+    for c in line.chars() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), c) => word.get_or_insert_default().push(c),
+            (None, '"' | '\'') => {
+                quote = Some(c);
+                word.get_or_insert_default();
+            }
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, c) => word.get_or_insert_default().push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(format!("unterminated quote in `{}`", line));
+    }
+    words.extend(word);
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::hooks::{split_command, Hook};
+    use crate::observer::{Action, Data};
+
+    #[test]
+    fn parse_and_match_test() {
+        let hook = "**/*.rs;action=Modified;run=cargo build --release".parse::<Hook>().unwrap();
+        assert_eq!(hook.command, vec!["cargo", "build", "--release"]);
+        assert!(hook.matches(&PathBuf::from("src/main.rs"), &Data::new(Action::Modified, None)));
+        assert!(!hook.matches(&PathBuf::from("src/main.rs"), &Data::new(Action::Created, None)));
+        assert!(!hook.matches(&PathBuf::from("README.md"), &Data::new(Action::Modified, None)));
+        assert!("**/*.rs;action=Modified".parse::<Hook>().is_err());
+    }
+
+    #[test]
+    fn split_command_test() {
+        assert_eq!(split_command(r#""C:\Program Files\tool.exe" --name 'a b' x"y z""#).unwrap(), vec![r"C:\Program Files\tool.exe", "--name", "a b", "xy z"]);
+        assert_eq!(split_command(r#"echo """#).unwrap(), vec!["echo", ""]);
+        assert!(split_command(r#"echo "open"#).is_err());
+
+        let hook = toml::from_str::<Hook>(r#"glob = "**"
+run = ["/opt/my tools/build", "--quiet"]"#).unwrap();
+        assert_eq!(hook.command, vec!["/opt/my tools/build", "--quiet"]);
+    }
+}
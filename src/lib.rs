@@ -0,0 +1,80 @@
+//!Very fast and lightweight file system observer that works directly with the system API.
+//!
+//!`Watcher` embeds the observers and the cache in another program, without the HTTP server of the `blazzy` binary:
+//!
+//!```no_run
+//!use blazzy::{Watcher, WatchEvent};
+//!use futures::StreamExt;
+//!
+//!# async fn run() -> Result<(), blazzy::BuildError> {
+//!let watcher = Watcher::builder()
+//!    .root(r"C:\projects")
+//!    .exclude("**/target/**")
+//!    .start()?;
+//!
+//!let mut events = watcher.events();
+//!while let Some(event) = events.next().await {
+//!    match event {
+//!        WatchEvent::Changed { path, data } => println!("{:?}: {}", data.action(), path.display()),
+//!        WatchEvent::Failed(error) => eprintln!("{}", error.message),
+//!        WatchEvent::Missed(n) => eprintln!("missed {} changes", n),
+//!    }
+//!}
+//!
+//!let latest = watcher.cache().latest().await;
+//!# Ok(())
+//!# }
+//!```
+
+pub mod observer;
+pub mod filter;
+pub mod config;
+pub mod storage;
+pub mod duration;
+pub mod watches;
+pub mod async_cacher;
+mod watcher;
+
+// Used by the `blazzy` binary, not part of the stable API
+#[doc(hidden)]
+pub mod auth;
+#[doc(hidden)]
+pub mod cli;
+#[doc(hidden)]
+pub mod server;
+#[doc(hidden)]
+pub mod websocket;
+#[doc(hidden)]
+pub mod event_bus;
+#[doc(hidden)]
+pub mod tls;
+#[doc(hidden)]
+pub mod hooks;
+#[doc(hidden)]
+pub mod webhook;
+#[doc(hidden)]
+pub mod reload;
+#[doc(hidden)]
+pub mod state;
+#[doc(hidden)]
+pub mod wal;
+#[doc(hidden)]
+pub mod export;
+#[doc(hidden)]
+pub mod shutdown;
+#[doc(hidden)]
+pub mod status;
+#[doc(hidden)]
+pub mod metrics;
+#[doc(hidden)]
+pub mod logging;
+#[doc(hidden)]
+pub mod tail;
+
+pub use watcher::{BuildError, Cache, WatchEvent, Watcher, WatcherBuilder};
+pub use observer::{Action, Data, EventSource, MetadataWrapper, ObserverError};
+pub use filter::{Filter, Patterns};
+pub use config::{Backend, Retention};
+pub use storage::{Event, HistoryQuery};
+pub use watches::{WatchError, WatchInfo, WatchStatus};
+pub use duration::HumanDuration;
@@ -0,0 +1,116 @@
+use std::path::PathBuf;
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing_appender::non_blocking::WorkerGuard;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+
+///Target of the per-event logs, off unless `logs` is set or the filter enables it
+pub const EVENTS_TARGET: &str = "blazzy::events";
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    ///Human readable lines
+    #[default]
+    Text,
+    ///One JSON object per line, with the fields of the current spans
+    Json,
+}
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogRotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogOptions {
+    ///Level or filter directives like `info,blazzy::webhook=debug`, `RUST_LOG` takes precedence
+    pub level: String,
+    pub format: LogFormat,
+    ///Write logs to this file instead of stdout, rotated files get a date suffix
+    pub file: Option<PathBuf>,
+    pub rotation: LogRotation,
+    ///How many rotated files to keep, all when not set
+    pub max_files: Option<usize>,
+}
+
+impl Default for LogOptions {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            format: LogFormat::Text,
+            file: None,
+            rotation: LogRotation::Daily,
+            max_files: None,
+        }
+    }
+}
+
+///Installs the global subscriber, logs written to a file are flushed when the guard is dropped
+pub fn init(options: &LogOptions, events: bool) -> Result<Option<WorkerGuard>, String> {
+    let directives = std::env::var(EnvFilter::DEFAULT_ENV).unwrap_or_else(|_| options.level.clone());
+    let filter = filter(&directives, events)?;
+
+    let (writer, guard) = match &options.file {
+        Some(file) => {
+            let dir = file.parent().filter(|dir| !dir.as_os_str().is_empty()).map(PathBuf::from).unwrap_or_else(|| PathBuf::from("."));
+            let name = file.file_name().ok_or_else(|| format!("log file {:?} has no file name", file))?;
+            let rotation = match options.rotation {
+                LogRotation::Never => Rotation::NEVER,
+                LogRotation::Hourly => Rotation::HOURLY,
+                LogRotation::Daily => Rotation::DAILY,
+            };
+            let mut appender = RollingFileAppender::builder().rotation(rotation).filename_prefix(name.to_string_lossy());
+            if let Some(max_files) = options.max_files {
+                appender = appender.max_log_files(max_files);
+            }
+            let appender = appender.build(dir).map_err(|e| format!("failed to open log file {:?}: {}", file, e))?;
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            (BoxMakeWriter::new(writer), Some(guard))
+        }
+        None => (BoxMakeWriter::new(std::io::stdout), None),
+    };
+
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(writer)
+        .with_ansi(options.file.is_none());
+    let result = match options.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().flatten_event(true).try_init(),
+    };
+    result.map_err(|e| format!("failed to install logger: {}", e))?;
+    Ok(guard)
+}
+
+///`directives` are the ones in effect, from `RUST_LOG` when it is set, event logs stay off unless they mention them
+fn filter(directives: &str, events: bool) -> Result<EnvFilter, String> {
+    let filter = EnvFilter::try_new(directives).map_err(|e| format!("invalid log filter: {}", e))?;
+    if events {
+        Ok(filter.add_directive(format!("{}=info", EVENTS_TARGET).parse().unwrap()))
+    } else if !directives.contains(EVENTS_TARGET) {
+        Ok(filter.add_directive(format!("{}=off", EVENTS_TARGET).parse().unwrap()))
+    } else {
+        Ok(filter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::logging::filter;
+
+    #[test]
+    fn filter_test() {
+        assert!(filter("info", false).unwrap().to_string().contains("blazzy::events=off"));
+        assert!(!filter("info,blazzy::events=debug", false).unwrap().to_string().contains("blazzy::events=off"));
+        assert!(filter("warn", true).unwrap().to_string().contains("blazzy::events=info"));
+        assert!(filter("info,[", false).is_err());
+    }
+}
@@ -1,119 +1,267 @@
-mod cli;
-mod server;
-mod observer;
-pub mod async_cacher;
-mod websocket;
-
-use std::{env, io};
 use std::fs::File;
+use std::io::{self, BufWriter};
 use std::path::PathBuf;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
 use clap::Parser;
-use tokio::{join, signal};
-use crate::async_cacher::{AsyncCacher, SharedAsyncCacher};
-use crate::cli::CLI;
-use crate::observer::{Data, Observer};
-use crate::server::Server;
-
-static TIME_METRICS: [&str; 7] = ["nsec", "micsec", "msec", "sec", "min", "hour", "day"];
+use futures::future::join_all;
+use tracing::{error, info, warn};
+use blazzy::async_cacher::{AsyncCacher, SharedAsyncCacher};
+use blazzy::cli::{Command, ConfigCommand, CLI};
+use blazzy::event_bus::SharedEventBus;
+use blazzy::hooks::HookRunner;
+use blazzy::observer::Data;
+use blazzy::reload::{ReloadRequest, Reloader};
+use blazzy::server::Server;
+use blazzy::shutdown::{Phase, SharedShutdown};
+use blazzy::state::StateFile;
+use blazzy::status::SharedStatus;
+use blazzy::tls::CertResolver;
+use blazzy::wal::Wal;
+use blazzy::config::Backend;
+use blazzy::duration::HumanDuration;
+use blazzy::storage::SqliteStore;
+use blazzy::watches::WatchManager;
+use blazzy::webhook::WebhookDispatcher;
+use blazzy::{logging, metrics, shutdown};
 
 #[tokio::main]
 async fn main() {
     let cli = CLI::parse();
-    let path = cli.get_path();
-    let host = cli.get_host();
-    let with_logs = cli.with_logs();
-    let with_autosave = cli.with_autosave();
-    let autosave_delay = cli.autosave_delay();
-    let connection_type = cli.get_connection_type();
+
+    if let Some(Command::Config { command: ConfigCommand::Validate }) = cli.get_command() {
+        let name = cli.get_config_path().map(|p| p.display().to_string()).unwrap_or("flags".to_string());
+        match cli.load_config() {
+            Ok(_) => println!("{} is valid", name),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Export { format, output, filter }) = cli.get_command() {
+        let result = cli.load_settings().map_err(|e| e.to_string()).and_then(|config| {
+            let count = match &output {
+                Some(path) => blazzy::export::export_offline(&config, format, &filter, &mut BufWriter::new(File::create(path).map_err(|e| e.to_string())?)),
+                None => blazzy::export::export_offline(&config, format, &filter, &mut io::stdout().lock()),
+            };
+            count.map_err(|e| e.to_string())
+        });
+        match result {
+            Ok(count) => eprintln!("Exported {} events", count),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1)
+            }
+        }
+        return;
+    }
+
+    if let Some(Command::Tail(args)) = cli.get_command() {
+        if let Err(e) = blazzy::tail::run(args).await {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+        return;
+    }
+
+    let config = match cli.load_config() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+    };
+
+    let _log_guard = match logging::init(&config.log, config.logs) {
+        Ok(guard) => guard,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1)
+        }
+    };
+    metrics::register();
+    let roots = config.roots.clone();
+    let filters = config.filters.clone();
+    let server_config = config.server.clone();
+    let tls = config.server.tls.clone().map(|tls| Arc::new(CertResolver::load(tls.cert, tls.key).expect("Failed to load TLS certificate")));
+    let with_autosave = config.autosave.enabled;
+    let autosave_delay = config.autosave.delay;
+    let webhooks = config.webhooks.clone();
+    let webhook_options = config.webhook_options.clone();
+    let hooks = config.hooks.clone();
+    let hook_options = config.hook_options.clone();
+    let state_file = Arc::new(StateFile::new(config.state.clone()));
+
+    if config.storage.backend == Backend::Sqlite {
+        let path = config.storage.path();
+        let store = SqliteStore::open(&path).unwrap_or_else(|e| panic!("Failed to open database {:?}: {}", path, e));
+        SharedAsyncCacher.set_store(Box::new(store));
+        info!(path = %path.display(), "Storing events in the database");
+    }
+    SharedAsyncCacher.set_retention(config.retention.clone());
+
+    // The database is durable on its own, snapshots and the WAL only restore the in-memory store
+    if config.storage.backend == Backend::Memory {
+        match state_file.load() {
+            Ok(entries) => {
+                for (path, data) in entries {
+                    SharedAsyncCacher.put(path, data);
+                }
+            }
+            Err(e) => error!(error = %e, "Failed to load state"),
+        }
+
+        if config.wal.enabled {
+            let wal = Wal::open(config.wal.path(&config.state), config.wal.sync).expect("Failed to open WAL");
+            let entries = wal.replay().expect("Failed to read WAL");
+            if !entries.is_empty() {
+                info!(count = entries.len(), "Replayed events from the WAL");
+            }
+            for (path, data) in entries {
+                SharedAsyncCacher.put(path, data);
+            }
+            SharedAsyncCacher.attach_wal(wal);
+        }
+    }
 
     let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
 
     let sender_arc = Arc::new(sender);
+    let (reload_sender, reload_receiver) = tokio::sync::mpsc::unbounded_channel();
+    let watches = Arc::new(Mutex::new(WatchManager::new(sender_arc.clone())));
+    let server_watches = watches.clone();
 
     let data_putter =  SharedAsyncCacher.clone();
     let data_saver_auto = SharedAsyncCacher.clone();
     let data_saver_exit =  SharedAsyncCacher.clone();
+    let state_file_auto = state_file.clone();
+    let shutdown_timeout = server_config.shutdown_timeout;
+    let backend = config.storage.backend;
 
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-
-    tokio::task::spawn( async move {
-        signal::ctrl_c().await.expect("Failed to install Ctrl+C handler");
-        r.store(false, Ordering::SeqCst);
-        autosave(data_saver_exit).await;
-        std::process::exit(0)
+    tokio::task::spawn(async {
+        shutdown::signal().await;
+        info!("Shutting down");
+        SharedShutdown.enter(Phase::Draining);
     });
 
-    println!("Start Observing");
+    #[cfg(unix)]
+    {
+        let resolver = tls.clone();
+        let reload = reload_sender.clone();
+        tokio::task::spawn(async move {
+            let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()).expect("Failed to install SIGHUP handler");
+            while hangup.recv().await.is_some() {
+                let _ = reload.send(ReloadRequest { reply: None });
+                if let Some(resolver) = &resolver {
+                    match resolver.reload() {
+                        Ok(_) => info!("TLS certificate reloaded"),
+                        Err(e) => error!(error = %e, "Failed to reload TLS certificate"),
+                    }
+                }
+            }
+        });
+    }
 
-    if running.load(Ordering::SeqCst) {
-        let server_task = tokio::task::spawn(async move {
+    info!("Starting");
 
-            let server = Server::init(host, connection_type).await.unwrap();
-            server.get_server().await.unwrap();
-        });
+    let server = Server::init(server_config, tls, reload_sender, server_watches).await.expect("Failed to start server");
+    let server_handle = server.handle();
+    let server_task = tokio::task::spawn(server.get_server());
 
-        let observer_task = tokio::task::spawn(async move {
-            let observer = Observer::init(&path).await;
-            observer.run(sender_arc.clone(), with_logs)
-        });
+    let mut dispatchers = vec![];
+    for webhook in webhooks {
+        let dispatcher = WebhookDispatcher::init(webhook, webhook_options.clone()).expect("Failed to create webhook spool");
+        dispatchers.push(tokio::task::spawn(dispatcher.run()));
+    }
 
-        let autosaver_task = tokio::task::spawn(async move {
-            if with_autosave {
-                let metric = autosave_delay.split(":").collect::<Vec<&str>>();
-                let mut delay = match TIME_METRICS.iter().find(|&&x| x == metric[1]) {
-                    Some(&"nsec") => tokio::time::interval(Duration::from_nanos(metric[0].parse::<u64>().unwrap())),
-                    Some(&"micsec") => tokio::time::interval(Duration::from_micros(metric[0].parse::<u64>().unwrap())),
-                    Some(&"msec") => tokio::time::interval(Duration::from_millis(metric[0].parse::<u64>().unwrap())),
-                    Some(&"sec") => tokio::time::interval(Duration::from_secs(metric[0].parse::<u64>().unwrap())),
-                    Some(&"min") => tokio::time::interval(Duration::from_secs(metric[0].parse::<u64>().unwrap() * 60)),
-                    Some(&"hour") => tokio::time::interval(Duration::from_secs(metric[0].parse::<u64>().unwrap() * 60_u64.pow(2))),
-                    Some(&"day") => tokio::time::interval(Duration::from_secs(metric[0].parse::<u64>().unwrap() * 60_u64.pow(2) * 24)),
-                    None => panic!("Unknown time metric"),
-                    Some(&&_) => panic!("Unknown time metric"),
-                };
-
-                loop {
-                    delay.tick().await;
-                    autosave(data_saver_auto.clone()).await;
-                }
+    if !hooks.is_empty() {
+        tokio::task::spawn(HookRunner::init(hooks, hook_options).run());
+    }
+
+    watches.lock().unwrap().apply(&roots, &filters);
+    tokio::task::spawn(Reloader::new(cli, watches.clone()).run(reload_receiver));
+    SharedStatus.set_ready();
+
+    let autosaver = tokio::task::spawn(async move {
+        if with_autosave {
+            let mut delay = tokio::time::interval(autosave_delay);
+
+            loop {
+                delay.tick().await;
+                autosave(data_saver_auto.clone(), &state_file_auto).await;
             }
-        });
+        }
+    });
+
+    let record = |(path, data): (PathBuf, Data)| {
+        SharedStatus.event(data.action());
+        data_putter.put(path.clone(), data.clone());
+        SharedEventBus.publish(path, data);
+    };
 
-        while let Some(data) = receiver.recv().await {
-            data_putter.put(data.0, data.1);
+    loop {
+        tokio::select! {
+            data = receiver.recv() => match data {
+                Some(data) => record(data),
+                None => break,
+            },
+            _ = SharedShutdown.reached(Phase::Draining) => break,
         }
+    }
 
+    // Stop observing and keep whatever they reported until then
+    let observers = watches.lock().unwrap().stop_all();
+    if tokio::time::timeout(shutdown_timeout, join_all(observers)).await.is_err() {
+        warn!(timeout = %HumanDuration(shutdown_timeout), "Observers did not stop in time");
+    }
+    while let Ok(data) = receiver.try_recv() {
+        record(data);
+    }
+
+    // Websocket clients get their last events and a close frame, webhooks spool what they collected
+    SharedShutdown.enter(Phase::Closing);
+    server_handle.stop(true).await;
+    let _ = server_task.await;
+    if tokio::time::timeout(shutdown_timeout, join_all(dispatchers)).await.is_err() {
+        warn!(timeout = %HumanDuration(shutdown_timeout), "Webhooks did not stop in time");
+    }
 
-        let _ = join!(server_task, observer_task, autosaver_task);
+    // Saves only happen at awaits of this task, so none is left half done or runs after the final one
+    autosaver.abort();
+    let _ = autosaver.await;
 
+    // The database is written as events arrive
+    if backend == Backend::Memory {
+        autosave(data_saver_exit, &state_file).await;
     }
+    info!("Stopped");
 }
 
-async fn get_cache<'a>(cacher: Arc<AsyncCacher>) -> Vec<(PathBuf, Data)> {
+async fn get_cache<'a>(cacher: Arc<AsyncCacher>) -> Result<Vec<(PathBuf, Data)>, String> {
     let mut vec = vec![];
     let data = cacher;
-    for data in data.get().await {
+    for data in data.checkpoint().await? {
         vec.push((data.0, data.1))
     }
-    vec
-}
-
-async fn save_state(data: Vec<(PathBuf, Data)>) -> io::Result<()> {
-    let path = env::current_exe().unwrap().parent().unwrap().join("state.json");
-    let file = File::create(path)?;
-    serde_json::to_writer(file, &data)?;
-    Ok(())
+    Ok(vec)
 }
 
-async fn autosave(data_saver: Arc<AsyncCacher>) {
-    let cache = get_cache(data_saver).await;
-    if let Err(e) = save_state(cache).await {
-        eprintln!("{}", e);
+async fn autosave(data_saver: Arc<AsyncCacher>, state_file: &StateFile) {
+    let timer = metrics::AUTOSAVE_DURATION.start_timer();
+    let result = match get_cache(data_saver.clone()).await {
+        Ok(cache) => state_file.save(&cache).map_err(|e| e.to_string()),
+        Err(e) => Err(e),
+    };
+    timer.observe_duration();
+    if let Err(e) = result {
+        error!(error = %e, "Failed to save state");
+        metrics::AUTOSAVE_FAILURES.inc();
+        SharedStatus.error(format!("failed to save state: {}", e));
     } else {
-        println!("State saved!");
+        data_saver.checkpoint_saved();
+        SharedStatus.saved();
+        info!("State saved");
     }
 }
\ No newline at end of file
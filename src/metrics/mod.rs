@@ -0,0 +1,95 @@
+use std::path::Path;
+use lazy_static::lazy_static;
+use prometheus::{exponential_buckets, register_histogram, register_int_counter, register_int_counter_vec, register_int_gauge, Encoder, Histogram, IntCounter, IntCounterVec, IntGauge, TextEncoder};
+use crate::auth::Scope;
+
+lazy_static! {
+    pub static ref EVENTS: IntCounterVec = register_int_counter_vec!(
+        "blazzy_events_total", "Events observed", &["action", "root"]
+    ).unwrap();
+    pub static ref FILTERED: IntCounterVec = register_int_counter_vec!(
+        "blazzy_events_filtered_total", "Events dropped by filters", &["root"]
+    ).unwrap();
+    pub static ref OVERFLOWS: IntCounterVec = register_int_counter_vec!(
+        "blazzy_overflows_total", "Times the system dropped changes because the change buffer was full", &["root"]
+    ).unwrap();
+    pub static ref DROPPED: IntCounterVec = register_int_counter_vec!(
+        "blazzy_events_dropped_total", "Events a consumer missed because it fell behind", &["consumer"]
+    ).unwrap();
+    pub static ref COALESCED: IntCounter = register_int_counter!(
+        "blazzy_events_coalesced_total", "Queued websocket events replaced by a newer one for the same path"
+    ).unwrap();
+    pub static ref EVICTED: IntCounter = register_int_counter!(
+        "blazzy_events_evicted_total", "Entries removed from the cache by retention"
+    ).unwrap();
+    pub static ref CACHE_SIZE: IntGauge = register_int_gauge!(
+        "blazzy_cache_entries", "Paths in memory or events in the database"
+    ).unwrap();
+    pub static ref WS_CLIENTS: IntGauge = register_int_gauge!(
+        "blazzy_websocket_clients", "Connected websocket clients"
+    ).unwrap();
+    pub static ref WS_QUEUE: IntGauge = register_int_gauge!(
+        "blazzy_websocket_queue_depth", "Events waiting to be sent, summed over every websocket client"
+    ).unwrap();
+    pub static ref AUTOSAVE_DURATION: Histogram = register_histogram!(
+        "blazzy_autosave_duration_seconds", "Time taken to save the state", exponential_buckets(0.001, 4.0, 8).unwrap()
+    ).unwrap();
+    pub static ref AUTOSAVE_FAILURES: IntCounter = register_int_counter!(
+        "blazzy_autosave_failures_total", "Failed attempts to save the state"
+    ).unwrap();
+    pub static ref WEBHOOK_FAILED: IntCounter = register_int_counter!(
+        "blazzy_webhook_batches_failed_total", "Webhook batches moved to failed/ after the endpoint refused them"
+    ).unwrap();
+    pub static ref DELIVERY_LATENCY: Histogram = register_histogram!(
+        "blazzy_delivery_latency_seconds", "Time from an observed change to the websocket frame carrying it", exponential_buckets(0.0005, 2.0, 14).unwrap()
+    ).unwrap();
+}
+
+///Registers every metric up front so scrapes see them before anything happened
+pub fn register() {
+    lazy_static::initialize(&EVENTS);
+    lazy_static::initialize(&FILTERED);
+    lazy_static::initialize(&OVERFLOWS);
+    lazy_static::initialize(&DROPPED);
+    lazy_static::initialize(&COALESCED);
+    lazy_static::initialize(&EVICTED);
+    lazy_static::initialize(&CACHE_SIZE);
+    lazy_static::initialize(&WS_CLIENTS);
+    lazy_static::initialize(&WS_QUEUE);
+    lazy_static::initialize(&AUTOSAVE_DURATION);
+    lazy_static::initialize(&AUTOSAVE_FAILURES);
+    lazy_static::initialize(&WEBHOOK_FAILED);
+    lazy_static::initialize(&DELIVERY_LATENCY);
+}
+
+///Every registered metric in the Prometheus text format, without `root` series outside of `scope`
+pub fn render(scope: &Scope) -> String {
+    let mut families = prometheus::gather();
+    for family in &mut families {
+        family.mut_metric().retain(|metric| metric.get_label().iter()
+            .find(|label| label.name() == "root")
+            .is_none_or(|label| scope.allows(Path::new(label.value()))));
+    }
+    let mut buffer = vec![];
+    TextEncoder::new().encode(&families, &mut buffer).unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::auth::{Access, Scope};
+    use crate::metrics::{render, EVENTS};
+
+    #[test]
+    fn render_scope_test() {
+        EVENTS.with_label_values(&["created", "/srv/public"]).inc();
+        EVENTS.with_label_values(&["created", "/srv/private"]).inc();
+        let scope = Scope { access: Access::Read, prefixes: vec![PathBuf::from("/srv/public")] };
+
+        let text = render(&scope);
+        assert!(text.contains("root=\"/srv/public\""));
+        assert!(!text.contains("/srv/private"));
+        assert!(render(&Scope::full()).contains("/srv/private"));
+    }
+}
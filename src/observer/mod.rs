@@ -1,21 +1,56 @@
 use std::ffi::{OsStr, OsString};
-use std::fs::{Metadata};
+use std::fmt::{Display, Formatter};
+use std::io;
 use std::os::windows::prelude::{OsStrExt, OsStringExt};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
 use std::ptr::null_mut;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::task::{Context, Poll};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use chrono::{DateTime, Local};
-use tokio::sync::mpsc::UnboundedSender;
-use winapi::um::fileapi::{CreateFileW, FindFirstChangeNotificationW, FindNextChangeNotification, GetFileAttributesExW, OPEN_EXISTING, WIN32_FILE_ATTRIBUTE_DATA};
-use winapi::um::handleapi::INVALID_HANDLE_VALUE;
+use tracing::{info, Span};
+use futures::Stream;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use winapi::um::fileapi::{CreateFileW, FindCloseChangeNotification, FindFirstChangeNotificationW, FindNextChangeNotification, GetFileAttributesExW, OPEN_EXISTING, WIN32_FILE_ATTRIBUTE_DATA};
+use winapi::um::handleapi::{CloseHandle, INVALID_HANDLE_VALUE};
 use winapi::um::synchapi::WaitForSingleObject;
 use winapi::um::winbase::{FILE_FLAG_BACKUP_SEMANTICS, ReadDirectoryChangesW};
 use winapi::um::winnt::{FILE_ATTRIBUTE_DIRECTORY, FILE_ATTRIBUTE_REPARSE_POINT, FILE_LIST_DIRECTORY, FILE_NOTIFY_CHANGE_ATTRIBUTES, FILE_NOTIFY_CHANGE_DIR_NAME, FILE_NOTIFY_CHANGE_FILE_NAME, FILE_NOTIFY_CHANGE_LAST_WRITE, FILE_NOTIFY_CHANGE_SECURITY, FILE_NOTIFY_CHANGE_SIZE, FILE_NOTIFY_INFORMATION, FILE_SHARE_DELETE, FILE_SHARE_READ, FILE_SHARE_WRITE, HANDLE};
-use serde::Serialize;
 use winapi::um::minwinbase::GetFileExInfoStandard;
+pub use blazzy_client::models::{Action, Data, MetadataWrapper};
+use crate::logging::EVENTS_TARGET;
+use crate::metrics;
+use crate::filter::Filter;
+use crate::watches::WatchState;
+
+#[derive(Debug)]
+pub enum ObserverError {
+    ///The root can't be watched, usually because it doesn't exist or access is denied
+    Open(PathBuf, io::Error),
+    Read(PathBuf, io::Error),
+    Wait(PathBuf, io::Error),
+    ///Nothing receives events anymore, the process is shutting down
+    Closed,
+}
+
+impl Display for ObserverError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ObserverError::Open(path, e) => write!(f, "failed to watch {}: {}", path.display(), e),
+            ObserverError::Read(path, e) => write!(f, "failed to read changes in {}: {}", path.display(), e),
+            ObserverError::Wait(path, e) => write!(f, "failed to wait for changes in {}: {}", path.display(), e),
+            ObserverError::Closed => write!(f, "event receiver is closed"),
+        }
+    }
+}
+
+impl std::error::Error for ObserverError {}
 
 pub struct Observer {
+    root: PathBuf,
     handle: HANDLE,
     dir_handle: HANDLE,
     buffer: [u8; 8192],
@@ -23,8 +58,8 @@ pub struct Observer {
 }
 
 impl Observer {
-    pub async fn init(path: &str) -> Self {
-        let path = OsString::from(path).encode_wide().chain(Some(0)).collect::<Vec<u16>>();
+    pub fn init(root: &Path) -> Result<Self, ObserverError> {
+        let path = root.as_os_str().encode_wide().chain(Some(0)).collect::<Vec<u16>>();
 
         let handle = unsafe {
             FindFirstChangeNotificationW(
@@ -36,8 +71,8 @@ impl Observer {
             )
         };
 
-        if handle.is_null() {
-            panic!("Failed to create change notification");
+        if handle.is_null() || handle == INVALID_HANDLE_VALUE {
+            return Err(ObserverError::Open(root.to_path_buf(), io::Error::last_os_error()));
         }
 
         let dir_handle = unsafe {
@@ -53,21 +88,26 @@ impl Observer {
         };
 
         if dir_handle == INVALID_HANDLE_VALUE {
-            panic!("Failed to open directory to read changes");
+            let e = io::Error::last_os_error();
+            unsafe { FindCloseChangeNotification(handle); }
+            return Err(ObserverError::Open(root.to_path_buf(), e));
         }
 
-        Self {
+        Ok(Self {
+            root: root.to_path_buf(),
             handle,
             dir_handle,
             buffer: [0u8; 8192],
             bytes_returned: 0,
-        }
+        })
     }
 
-    pub fn run(self, sender: Arc<UnboundedSender<(PathBuf, Data)>>, with_logs: bool) {
+    ///Blocks until `stop` or the watch is stopped or fails, filters are read on every change so they can be swapped while running
+    pub fn run(self, sender: Arc<UnboundedSender<(PathBuf, Data)>>, state: Arc<WatchState>, stop: &AtomicBool) -> Result<(), ObserverError> {
         let mut buffer = self.buffer;
         let mut bytes_returned = self.bytes_returned;
-        loop {
+        let root = self.root.to_string_lossy().to_string();
+        while !stop.load(Ordering::SeqCst) && !state.stop.load(Ordering::SeqCst) {
             unsafe {
                 let result = WaitForSingleObject(self.handle, 1);
                 if result == 0 { // WAIT_OBJECT_0
@@ -88,7 +128,13 @@ impl Observer {
                     );
 
                     if success == 0 {
-                        panic!("Error reading changes from directory");
+                        return Err(ObserverError::Read(self.root.clone(), io::Error::last_os_error()));
+                    }
+
+                    // The buffer was too small for the changes since the last call, they are lost
+                    if bytes_returned == 0 {
+                        state.overflowed();
+                        metrics::OVERFLOWS.with_label_values(&[&root]).inc();
                     }
 
                     let mut offset = 0;
@@ -110,19 +156,18 @@ impl Observer {
                             _ => Action::Unknown,
                         };
 
-                        if with_logs { println!("{action:?}: {filename:?}"); }
-
-                        let file_path = format!("C:\\{}", filename);
-                        match Self::get_file_metadata(&file_path) {
-                            Ok(metadata) => {
-                                sender.send((PathBuf::from(file_path.to_string()), Data::new(action, Some(metadata)))).unwrap();
-                            }
-                            Err(_) => {
-                                sender.send((PathBuf::from(file_path.to_string()), Data::new(action, None))).unwrap();
-                            }
-                        };
-
+                        let file_path = self.root.join(&*filename);
+                        if !state.filter.read().unwrap().allows(&file_path) {
+                            state.filtered.fetch_add(1, Ordering::Relaxed);
+                            metrics::FILTERED.with_label_values(&[&root]).inc();
+                        } else {
+                            state.events.fetch_add(1, Ordering::Relaxed);
+                            metrics::EVENTS.with_label_values(&[&String::from(action), &root]).inc();
+                            info!(target: EVENTS_TARGET, action = ?action, path = %file_path.display(), "Observed change");
 
+                            let metadata = Self::get_file_metadata(&file_path.to_string_lossy()).ok();
+                            sender.send((file_path, Data::observed(action, metadata))).map_err(|_| ObserverError::Closed)?;
+                        }
 
                         offset += notify_info.NextEntryOffset as usize;
                         if notify_info.NextEntryOffset == 0 {
@@ -131,24 +176,25 @@ impl Observer {
                     }
 
                     if FindNextChangeNotification(self.handle) == 0 {
-                        panic!("Error when calling FindNextChangeNotification");
+                        return Err(ObserverError::Wait(self.root.clone(), io::Error::last_os_error()));
                     }
                 } else if result == 0x102 { // WAIT_TIMEOUT
                     continue
                 } else {
-                    panic!("Error while waiting: {:?}", result);
+                    return Err(ObserverError::Wait(self.root.clone(), io::Error::last_os_error()));
                 }
             }
         }
+        Ok(())
     }
 
-    fn get_file_metadata(path: &str) -> std::io::Result<MetadataWrapper> {
+    fn get_file_metadata(path: &str) -> io::Result<MetadataWrapper> {
         let path_wide: Vec<u16> = OsStr::new(path).encode_wide().chain(Some(0)).collect();
         unsafe {
             let mut file_info: WIN32_FILE_ATTRIBUTE_DATA = std::mem::zeroed();
 
             if GetFileAttributesExW(path_wide.as_ptr(), GetFileExInfoStandard, &mut file_info as *mut _ as *mut _) == 0 {
-                return Err(std::io::Error::last_os_error());
+                return Err(io::Error::last_os_error());
             }
 
             let file_type = if file_info.dwFileAttributes & FILE_ATTRIBUTE_DIRECTORY != 0 {
@@ -188,56 +234,83 @@ impl Observer {
 
 }
 
-#[derive(Serialize, Debug, Clone, PartialEq)]
-pub struct MetadataWrapper {
-    file_type: String,
-    is_dir: bool,
-    is_file: bool,
-    is_symlink: bool,
-    len_in_bytes: u64,
-    permissions: String,
-    modified: String,
-    accessed: String,
-    created: String,
+///Changes under a root as a `Stream`, the blocking observer runs on its own thread until the source is dropped.
+///A failure is yielded as the last item
+pub struct EventSource {
+    receiver: UnboundedReceiver<(PathBuf, Data)>,
+    error: Arc<Mutex<Option<ObserverError>>>,
+    stop: Arc<AtomicBool>,
 }
 
-impl From<&Metadata> for MetadataWrapper {
-    fn from(metadata: &Metadata) -> Self {
-        MetadataWrapper {
-            file_type: format!("{:?}", metadata.file_type()),
-            is_dir: metadata.is_dir(),
-            is_file: metadata.is_file(),
-            is_symlink: metadata.is_symlink(),
-            len_in_bytes: metadata.len(),
-            permissions: format!("{:?}", metadata.permissions()),
-            modified: format!("{:?}", metadata.modified().unwrap()),
-            accessed: format!("{:?}", metadata.accessed().unwrap()),
-            created: format!("{:?}", metadata.created().unwrap())
-        }
+impl EventSource {
+    pub async fn new(root: impl Into<PathBuf>, filter: Filter) -> Result<Self, ObserverError> {
+        Self::open(root.into(), Arc::new(WatchState::new(filter))).await
     }
-}
 
-#[derive(Debug, Serialize, Clone, PartialEq)]
-pub struct Data {
-    action: Action,
-    metadata: Option<MetadataWrapper>,
+    ///Observes `root` with the filter and counters of `state`, returns once the observer is set up
+    pub async fn open(root: PathBuf, state: Arc<WatchState>) -> Result<Self, ObserverError> {
+        let (sender, receiver) = unbounded_channel();
+        let error = Arc::new(Mutex::new(None));
+        let stop = Arc::new(AtomicBool::new(false));
+        let (ready, started) = oneshot::channel();
+
+        let thread_error = error.clone();
+        let thread_stop = stop.clone();
+        let span = Span::current();
+        let path = root.clone();
+        std::thread::Builder::new()
+            .name(format!("observer {}", root.display()))
+            .spawn(move || {
+                let _span = span.enter();
+                // The observer holds raw handles and isn't Send, so it's opened by the thread that runs it
+                let observer = match Observer::init(&root) {
+                    Ok(observer) => observer,
+                    Err(e) => {
+                        let _ = ready.send(Err(e));
+                        return;
+                    }
+                };
+                let _ = ready.send(Ok(()));
+
+                // The stream ends when the last sender is gone, the error has to be in place before that
+                let sender = Arc::new(sender);
+                let result = observer.run(sender.clone(), state, &thread_stop);
+                if let Err(e) = result {
+                    *thread_error.lock().unwrap() = Some(e);
+                }
+                drop(sender);
+            })
+            .map_err(|e| ObserverError::Open(path, e))?;
+
+        started.await.unwrap_or(Err(ObserverError::Closed))?;
+        Ok(Self { receiver, error, stop })
+    }
 }
 
-impl Data {
-    pub fn new(action: Action, metadata: Option<MetadataWrapper>) -> Self {
-        Self {
-            action,
-            metadata
+impl Stream for EventSource {
+    type Item = Result<(PathBuf, Data), ObserverError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        match self.receiver.poll_recv(cx) {
+            Poll::Ready(Some(change)) => Poll::Ready(Some(Ok(change))),
+            Poll::Ready(None) => Poll::Ready(self.error.lock().unwrap().take().map(Err)),
+            Poll::Pending => Poll::Pending,
         }
     }
 }
 
-#[derive(Debug, Serialize, Copy, Clone, PartialEq)]
-pub enum Action {
-    Created,
-    Deleted,
-    Modified,
-    RenamedIn,
-    RenamedOut,
-    Unknown
+impl Drop for EventSource {
+    fn drop(&mut self) {
+        // The observer notices within a millisecond and its thread ends
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Observer {
+    fn drop(&mut self) {
+        unsafe {
+            FindCloseChangeNotification(self.handle);
+            CloseHandle(self.dir_handle);
+        }
+    }
 }
@@ -0,0 +1,46 @@
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::sync::oneshot;
+use tracing::{error, info};
+use crate::async_cacher::SharedAsyncCacher;
+use crate::cli::CLI;
+use crate::watches::SharedWatches;
+
+pub type ReloadSender = UnboundedSender<ReloadRequest>;
+
+///Asks the reloader to re-read the configuration, `reply` receives the outcome when given
+pub struct ReloadRequest {
+    pub reply: Option<oneshot::Sender<Result<(), String>>>,
+}
+
+///Re-reads `--config` (with the original flags applied on top) and updates roots, filters and retention.
+///The cache, server and connected clients are left untouched, listener and TLS settings need a restart
+pub struct Reloader {
+    cli: CLI,
+    watches: SharedWatches,
+}
+
+impl Reloader {
+    pub fn new(cli: CLI, watches: SharedWatches) -> Self {
+        Self { cli, watches }
+    }
+
+    pub async fn run(mut self, mut requests: UnboundedReceiver<ReloadRequest>) {
+        while let Some(request) = requests.recv().await {
+            let result = self.reload();
+            match &result {
+                Ok(_) => info!("Configuration reloaded"),
+                Err(e) => error!(error = %e, "Failed to reload configuration"),
+            }
+            if let Some(reply) = request.reply {
+                let _ = reply.send(result);
+            }
+        }
+    }
+
+    fn reload(&mut self) -> Result<(), String> {
+        let config = self.cli.load_config().map_err(|e| e.to_string())?;
+        self.watches.lock().unwrap().apply(&config.roots, &config.filters);
+        SharedAsyncCacher.set_retention(config.retention);
+        Ok(())
+    }
+}
@@ -1,61 +1,483 @@
+use std::fmt::{Display, Formatter};
 use std::io;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::Duration;
 use actix_web::{App, HttpResponse, HttpServer, Responder, web};
-use actix_web::middleware::Logger;
-use actix_web::web::{get};
-use env_logger::Env;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Next};
+use actix_web::web::{delete, get, post, Bytes};
+use futures::TryStreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::oneshot;
+use tracing::{info, info_span, warn, Instrument};
+use crate::auth::{Auth, Scope};
 use crate::async_cacher::{AsyncCacher, SharedAsyncCacher};
+use crate::config::ServerConfig;
+use crate::export::{ExportFilter, ExportFormat};
+use crate::filter::Filter;
+use crate::hooks::SharedHookRuns;
+use crate::observer::Action;
+use crate::reload::{ReloadRequest, ReloadSender};
+use crate::shutdown::{Phase, SharedShutdown};
+use crate::status::SharedStatus;
+use crate::storage::{parse_time, HistoryQuery};
+use crate::tls::CertResolver;
+use crate::duration::HumanDuration;
+use crate::watches::{SharedWatches, WatchStatus};
+
+///Upper bound of `limit` for `/events`
+const MAX_EVENTS: usize = 10_000;
 
 pub struct Server {
     server: actix_web::dev::Server
 }
 
 impl Server {
-    pub async fn init(host: (String, u16), connection_type: ConnectionType) -> io::Result<Self> {
-        env_logger::init_from_env(Env::default().default_filter_or("info"));
+    pub async fn init(config: ServerConfig, tls: Option<Arc<CertResolver>>, reload: ReloadSender, watches: SharedWatches) -> io::Result<Self> {
         let cacher = SharedAsyncCacher.clone();
+        let auth = Auth::new(config.tokens);
+        let ws_options = config.websocket;
+        let socket_mode = config.socket_mode;
+        let connection_type = match config.connection_type {
+            Some(ConnectionType::Unknown) | None => panic!("Unknown connection type"),
+            Some(connection_type) => connection_type,
+        };
+
+        let shutdown_timeout = config.shutdown_timeout;
+        let mut server = HttpServer::new(move || {
+            App::new()
+                .wrap(from_fn(crate::auth::authenticate))
+                .wrap(from_fn(trace_request))
+                .app_data(web::Data::new(auth.clone()))
+                .app_data(web::Data::new(cacher.clone()))
+                .app_data(web::Data::new(ws_options.clone()))
+                .app_data(web::Data::new(reload.clone()))
+                .app_data(web::Data::new(watches.clone()))
+                .configure(|cfg| Self::routes(cfg, connection_type))
+        })
+            // Shutdown is coordinated in main so clients are notified and state is flushed first
+            .disable_signals()
+            // Whole seconds only, rounded up so a sub-second timeout still gives requests a chance to finish
+            .shutdown_timeout(shutdown_timeout.as_secs() + u64::from(shutdown_timeout.subsec_nanos() > 0));
+
+        for listener in config.listen {
+            server = match listener {
+                Listener::Tcp(host, port) => match &tls {
+                    Some(resolver) => server.bind_rustls_0_23((host, port), resolver.server_config())?,
+                    None => server.bind((host, port))?,
+                },
+                #[cfg(unix)]
+                Listener::Unix(path) => {
+                    let server = server.bind_uds(&path)?;
+                    Self::set_socket_mode(&path, socket_mode)?;
+                    server
+                }
+                #[cfg(not(unix))]
+                Listener::Unix(path) => {
+                    return Err(io::Error::new(io::ErrorKind::Unsupported, format!("Unix sockets are not supported on this platform: {}", path.display())))
+                }
+            };
+        }
+
+        Ok(Self{ server: server.run() })
+    }
+
+    fn routes(cfg: &mut web::ServiceConfig, connection_type: ConnectionType) {
+        cfg.route("/health", get().to(Self::health));
+        cfg.route("/healthz", get().to(Self::healthz));
+        cfg.route("/readyz", get().to(Self::readyz));
+        cfg.route("/status", get().to(Self::status));
+        cfg.route("/metrics", get().to(Self::metrics));
+        cfg.route("/hooks/runs", get().to(Self::get_hook_runs));
+        cfg.route("/admin/reload", post().to(Self::reload));
+        cfg.route("/watches", get().to(Self::get_watches));
+        cfg.route("/watches", post().to(Self::add_watch));
+        cfg.route("/watches/{id}", delete().to(Self::remove_watch));
+        cfg.route("/events", get().to(Self::get_events));
+        cfg.route("/export", get().to(Self::export));
         match connection_type {
             ConnectionType::Websocket => {
-                let server = HttpServer::new(|| App::new().wrap(Logger::default()).route("/", get().to(crate::websocket::ws_index)))
-                    .bind(host)?
-                    .run();
-                Ok(Self{ server })
+                cfg.route("/", get().to(crate::websocket::ws_index));
             }
             ConnectionType::REST => {
-                let server = HttpServer::new( move || {
-                    App::new()
-                        .wrap(Logger::default())
-                        .app_data(web::Data::new(cacher.clone()))
-                        .route("/", get().to(Self::get_cache))
-                })
-                    .bind(host)?
-                    .run();
-
-                Ok(Self{ server })
+                cfg.route("/", get().to(Self::get_cache));
             }
-            ConnectionType::Unknown => panic!("Unknown connection type")
+            ConnectionType::Unknown => {}
         }
+    }
+
+    #[cfg(unix)]
+    fn set_socket_mode(path: &Path, socket_mode: u32) -> io::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(socket_mode))
+    }
 
+    pub fn handle(&self) -> actix_web::dev::ServerHandle {
+        self.server.handle()
     }
 
     pub fn get_server(self) -> actix_web::dev::Server {
         self.server
     }
 
-    async fn get_cache(data: web::Data<Arc<AsyncCacher>>) -> impl Responder {
+    async fn get_cache(data: web::Data<Arc<AsyncCacher>>, scope: web::ReqData<Scope>) -> impl Responder {
+        let latest = match data.get().await {
+            Ok(latest) => latest,
+            Err(e) => return HttpResponse::InternalServerError().json(json!({ "error": e })),
+        };
         let mut vec = vec![];
-        for data in data.get().await {
-            vec.push(data)
+        for data in latest {
+            if scope.allows(&data.0) {
+                vec.push(data)
+            }
         }
 
         HttpResponse::Accepted().json(vec)
 
     }
+
+    async fn reload(reload: web::Data<ReloadSender>) -> impl Responder {
+        let (reply, result) = oneshot::channel();
+        if reload.send(ReloadRequest { reply: Some(reply) }).is_err() {
+            return HttpResponse::ServiceUnavailable().json(json!({ "error": "reloader is not running" }));
+        }
+        match result.await {
+            Ok(Ok(())) => HttpResponse::Ok().json(json!({ "reloaded": true })),
+            Ok(Err(e)) => HttpResponse::BadRequest().json(json!({ "error": e })),
+            Err(_) => HttpResponse::ServiceUnavailable().json(json!({ "error": "reloader is not running" })),
+        }
+    }
+
+    async fn get_watches(watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>) -> impl Responder {
+        let list = watches.lock().unwrap().list().into_iter()
+            .filter(|watch| scope.allows(&watch.path))
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(list)
+    }
+
+    ///Watch health for people and dashboards: the visible watches, 503 while one of them is failing.
+    ///Unlike `/healthz` and `/readyz` it ignores startup and shutdown, probes should use those
+    async fn health(watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>) -> impl Responder {
+        let list = watches.lock().unwrap().list().into_iter()
+            .filter(|watch| scope.allows(&watch.path))
+            .collect::<Vec<_>>();
+        if list.iter().any(|watch| watch.status == WatchStatus::Failed) {
+            HttpResponse::ServiceUnavailable().json(json!({ "status": "degraded", "watches": list }))
+        } else {
+            HttpResponse::Ok().json(json!({ "status": "ok", "watches": list }))
+        }
+    }
+
+    ///Liveness probe, answers as long as the server handles requests, whatever the watches do
+    async fn healthz() -> impl Responder {
+        HttpResponse::Ok().json(json!({ "status": "ok" }))
+    }
+
+    ///Ready once the state is restored and observers are started, until shutdown begins or an observer fails
+    async fn readyz(watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>) -> impl Responder {
+        let phase = SharedShutdown.phase();
+        let failed = watches.lock().unwrap().list().into_iter()
+            .filter(|watch| watch.status == WatchStatus::Failed && scope.allows(&watch.path))
+            .map(|watch| watch.path)
+            .collect::<Vec<_>>();
+        let reason = if !SharedStatus.is_ready() {
+            Some("starting".to_string())
+        } else if phase != Phase::Running {
+            Some("shutting down".to_string())
+        } else if !failed.is_empty() {
+            Some(format!("{} failed observer(s)", failed.len()))
+        } else {
+            None
+        };
+        match reason {
+            None => HttpResponse::Ok().json(json!({ "status": "ready" })),
+            Some(reason) => HttpResponse::ServiceUnavailable().json(json!({ "status": "not ready", "reason": reason, "failed": failed })),
+        }
+    }
+
+    async fn status(data: web::Data<Arc<AsyncCacher>>, watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>) -> impl Responder {
+        let list = watches.lock().unwrap().list().into_iter()
+            .filter(|watch| scope.allows(&watch.path))
+            .collect::<Vec<_>>();
+        let uptime = SharedStatus.uptime();
+        HttpResponse::Ok().json(json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "phase": SharedShutdown.phase(),
+            "ready": SharedStatus.is_ready(),
+            "started_at": SharedStatus.started_at(),
+            "uptime": HumanDuration(Duration::from_secs(uptime.as_secs())).to_string(),
+            "uptime_secs": uptime.as_secs(),
+            "watches": list,
+            "events": SharedStatus.events(),
+            "cache_size": data.len().await.ok(),
+            "history": data.keeps_history().await.ok(),
+            "clients": SharedStatus.clients(),
+            "last_autosave": SharedStatus.last_autosave(),
+            "last_error": SharedStatus.last_error(&scope),
+        }))
+    }
+
+    async fn metrics(data: web::Data<Arc<AsyncCacher>>, scope: web::ReqData<Scope>) -> impl Responder {
+        if let Ok(len) = data.len().await {
+            crate::metrics::CACHE_SIZE.set(len as i64);
+        }
+        HttpResponse::Ok()
+            .content_type("text/plain; version=0.0.4")
+            .body(crate::metrics::render(&scope))
+    }
+
+    async fn add_watch(watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>, body: web::Json<NewWatch>) -> impl Responder {
+        let NewWatch { path, filters } = body.into_inner();
+        let Some(path) = watch_path(&path) else {
+            return HttpResponse::BadRequest().json(json!({ "error": "path must be absolute, without `.` or `..` components" }));
+        };
+        if !scope.allows(&path) {
+            return HttpResponse::Forbidden().json(json!({ "error": "path is outside of the token scope" }));
+        }
+        if !path.is_dir() {
+            return HttpResponse::BadRequest().json(json!({ "error": format!("{} is not a directory", path.display()) }));
+        }
+        match watches.lock().unwrap().add(path, filters) {
+            Some(watch) => HttpResponse::Created().json(watch),
+            None => HttpResponse::Conflict().json(json!({ "error": "path is already watched" })),
+        }
+    }
+
+    async fn remove_watch(watches: web::Data<SharedWatches>, scope: web::ReqData<Scope>, id: web::Path<u64>) -> impl Responder {
+        let mut watches = watches.lock().unwrap();
+        match watches.get(*id) {
+            Some(watch) if scope.allows(&watch.path) => {
+                watches.remove(*id);
+                HttpResponse::NoContent().finish()
+            }
+            _ => HttpResponse::NotFound().json(json!({ "error": "no such watch" })),
+        }
+    }
+
+    async fn get_events(data: web::Data<Arc<AsyncCacher>>, scope: web::ReqData<Scope>, params: web::Query<EventsParams>) -> impl Responder {
+        let params = params.into_inner();
+        let (since, until) = match (params.since.as_deref().map(parse_time).transpose(), params.until.as_deref().map(parse_time).transpose()) {
+            (Ok(since), Ok(until)) => (since, until),
+            (Err(e), _) | (_, Err(e)) => return HttpResponse::BadRequest().json(json!({ "error": e })),
+        };
+        let query = HistoryQuery {
+            path: params.path,
+            action: params.action,
+            since,
+            until,
+            before: params.before,
+            limit: params.limit.unwrap_or(100).clamp(1, MAX_EVENTS),
+            scope: scope.into_inner(),
+        };
+        match data.history(query).await {
+            Ok(events) => HttpResponse::Ok().json(events),
+            Err(e) => HttpResponse::NotImplemented().json(json!({ "error": e })),
+        }
+    }
+
+    async fn export(data: web::Data<Arc<AsyncCacher>>, scope: web::ReqData<Scope>, params: web::Query<ExportParams>) -> impl Responder {
+        let ExportParams { format, filter } = params.into_inner();
+        match crate::export::stream(data.get_ref().clone(), format, filter, scope.into_inner()) {
+            Ok(chunks) => HttpResponse::Ok()
+                .content_type(format.content_type())
+                .insert_header(("Content-Disposition", format!("attachment; filename=\"events.{}\"", format.extension())))
+                .streaming(chunks.map_ok(Bytes::from)),
+            Err(e) => HttpResponse::BadRequest().json(json!({ "error": e })),
+        }
+    }
+
+    async fn get_hook_runs(scope: web::ReqData<Scope>) -> impl Responder {
+        let runs = SharedHookRuns.lock().unwrap().iter()
+            .filter(|run| scope.allows(run.path()))
+            .cloned()
+            .collect::<Vec<_>>();
+        HttpResponse::Ok().json(runs)
+    }
+}
+
+///Runs every request in its own span and logs its outcome
+async fn trace_request(req: ServiceRequest, next: Next<impl MessageBody>) -> Result<ServiceResponse<impl MessageBody>, actix_web::Error> {
+    let span = info_span!(
+        "request",
+        method = %req.method(),
+        path = %req.path(),
+        peer = req.connection_info().peer_addr().unwrap_or("-").to_string(),
+    );
+    let started = std::time::Instant::now();
+    let result = next.call(req).instrument(span.clone()).await;
+    let _enter = span.enter();
+    let elapsed_ms = started.elapsed().as_secs_f64() * 1000.0;
+    match &result {
+        Ok(res) if res.status().is_server_error() => warn!(status = res.status().as_u16(), elapsed_ms, "Request failed"),
+        Ok(res) => info!(status = res.status().as_u16(), elapsed_ms, "Request handled"),
+        Err(e) => warn!(status = e.as_response_error().status_code().as_u16(), error = %e, elapsed_ms, "Request rejected"),
+    }
+    result
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct EventsParams {
+    path: Option<PathBuf>,
+    action: Option<Action>,
+    ///RFC 3339 time, or a duration before now like `1h`
+    since: Option<String>,
+    until: Option<String>,
+    ///Only events with a lower id, pass the last id of a page to get the next one
+    before: Option<i64>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct ExportParams {
+    #[serde(default)]
+    format: ExportFormat,
+    #[serde(flatten)]
+    filter: ExportFilter,
 }
 
+///`path` without `.` components, `None` when it is relative or goes up with `..`, which scope prefixes can't be checked against
+fn watch_path(path: &Path) -> Option<PathBuf> {
+    if !path.is_absolute() || path.components().any(|c| matches!(c, Component::ParentDir | Component::CurDir)) {
+        return None;
+    }
+    Some(path.components().collect())
+}
 
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct NewWatch {
+    path: PathBuf,
+    #[serde(default)]
+    filters: Filter,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(try_from = "String")]
+pub enum Listener {
+    ///Host name or IP address without brackets, and port
+    Tcp(String, u16),
+    Unix(PathBuf),
+}
+
+impl FromStr for Listener {
+    type Err = String;
+
+    ///`127.0.0.1:8080`, `[::1]:8080`, `localhost:8080` or `unix:/path/to.sock`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("missing socket path after `unix:`".to_string());
+            }
+            return Ok(Listener::Unix(PathBuf::from(path)));
+        }
+
+        let (host, port) = if let Some(rest) = s.strip_prefix('[') {
+            let (address, rest) = rest.split_once(']').ok_or_else(|| format!("missing `]` in `{}`", s))?;
+            address.parse::<Ipv6Addr>().map_err(|_| format!("`{}` is not an IPv6 address", address))?;
+            let port = rest.strip_prefix(':').ok_or_else(|| format!("missing port in `{}`, e.g. [{}]:8080", s, address))?;
+            (address, port)
+        } else {
+            let (host, port) = s.rsplit_once(':').ok_or_else(|| format!("missing port in `{}`, e.g. {}:8080", s, s))?;
+            if host.contains(':') {
+                return Err(format!("IPv6 addresses must be in brackets, e.g. [{}]:{}", host, port));
+            }
+            if host.parse::<Ipv4Addr>().is_err() && !is_hostname(host) {
+                return Err(format!("`{}` is not a valid IPv4 address or host name", host));
+            }
+            (host, port)
+        };
+        let port = port.parse::<u16>().map_err(|_| format!("`{}` is not a valid port (0-65535)", port))?;
+        Ok(Listener::Tcp(host.to_string(), port))
+    }
+}
+
+impl Display for Listener {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listener::Tcp(host, port) if host.contains(':') => write!(f, "[{}]:{}", host, port),
+            Listener::Tcp(host, port) => write!(f, "{}:{}", host, port),
+            Listener::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+fn is_hostname(host: &str) -> bool {
+    !host.is_empty() && host.len() <= 253 && host.split('.').all(|label| {
+        !label.is_empty() && label.len() <= 63
+            && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+            && !label.starts_with('-') && !label.ends_with('-')
+    })
+}
+
+impl TryFrom<String> for Listener {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
 pub enum ConnectionType {
     Websocket,
     REST,
+    #[serde(skip)]
     Unknown
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+    use actix_web::{web, App};
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use serde_json::json;
+    use tokio::sync::mpsc::unbounded_channel;
+    use crate::auth::Auth;
+    use crate::server::{Listener, Server};
+    use crate::watches::WatchManager;
+
+    #[test]
+    fn listener_test() {
+        assert_eq!("127.0.0.1:8080".parse(), Ok(Listener::Tcp("127.0.0.1".to_string(), 8080)));
+        assert_eq!("[::1]:8080".parse(), Ok(Listener::Tcp("::1".to_string(), 8080)));
+        assert_eq!("my-host.local:80".parse(), Ok(Listener::Tcp("my-host.local".to_string(), 80)));
+        assert_eq!("unix:/run/blazzy.sock".parse(), Ok(Listener::Unix(PathBuf::from("/run/blazzy.sock"))));
+        assert_eq!("[::1]:8080".parse::<Listener>().unwrap().to_string(), "[::1]:8080");
+
+        for bad in ["localhost", "::1:8080", "[::1]", "[::1:8080", "host:http", "host:70000", "bad_host:80", ":80", "unix:"] {
+            assert!(bad.parse::<Listener>().is_err(), "{} should be rejected", bad);
+        }
+    }
+
+    #[actix_web::test]
+    async fn add_watch_scope_test() {
+        let root = std::env::temp_dir().join("blazzy-scope-test");
+        std::fs::create_dir_all(&root).unwrap();
+        let watches = Arc::new(Mutex::new(WatchManager::new(Arc::new(unbounded_channel().0))));
+        let token = format!("admin:t1={}", root.display()).parse().unwrap();
+        let app = init_service(App::new()
+            .wrap(from_fn(crate::auth::authenticate))
+            .app_data(web::Data::new(Auth::new(vec![token])))
+            .app_data(web::Data::new(watches.clone()))
+            .route("/watches", web::post().to(Server::add_watch))).await;
+
+        for (path, status) in [(root.join(".."), StatusCode::BAD_REQUEST), (root.join(".").join("missing"), StatusCode::BAD_REQUEST), (std::env::temp_dir(), StatusCode::FORBIDDEN)] {
+            let req = TestRequest::post().uri("/watches")
+                .insert_header(("Authorization", "Bearer t1"))
+                .set_json(json!({ "path": path }))
+                .to_request();
+            assert_eq!(call_service(&app, req).await.status(), status, "{}", path.display());
+        }
+        assert!(watches.lock().unwrap().list().is_empty());
+    }
+}
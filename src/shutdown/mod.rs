@@ -0,0 +1,70 @@
+use lazy_static::lazy_static;
+use serde::Serialize;
+use tokio::signal;
+use tokio::sync::watch;
+
+lazy_static! {
+    pub static ref SharedShutdown: Shutdown = Shutdown::new();
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Running,
+    ///Observers are stopped and pending events are moved into the cache
+    Draining,
+    ///Clients get their last events and are disconnected, pending batches are spooled
+    Closing,
+}
+
+///Coordinates the steps of a shutdown, phases only ever move forward
+pub struct Shutdown {
+    phase: watch::Sender<Phase>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Self { phase: watch::channel(Phase::Running).0 }
+    }
+
+    pub fn enter(&self, phase: Phase) {
+        self.phase.send_if_modified(|current| {
+            let forward = *current < phase;
+            if forward {
+                *current = phase;
+            }
+            forward
+        });
+    }
+
+    pub fn phase(&self) -> Phase {
+        *self.phase.borrow()
+    }
+
+    ///Resolves once `phase` or a later one is entered
+    pub async fn reached(&self, phase: Phase) {
+        let _ = self.phase.subscribe().wait_for(|current| *current >= phase).await;
+    }
+}
+
+///Ctrl+C and SIGTERM, or closing the console and system shutdown on Windows
+pub async fn signal() {
+    #[cfg(unix)]
+    {
+        let mut terminate = signal::unix::signal(signal::unix::SignalKind::terminate()).expect("Failed to install SIGTERM handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(windows)]
+    {
+        let mut close = signal::windows::ctrl_close().expect("Failed to install Ctrl+Close handler");
+        let mut shutdown = signal::windows::ctrl_shutdown().expect("Failed to install shutdown handler");
+        tokio::select! {
+            _ = signal::ctrl_c() => {}
+            _ = close.recv() => {}
+            _ = shutdown.recv() => {}
+        }
+    }
+}
@@ -0,0 +1,314 @@
+use std::env;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use clap::ValueEnum;
+use serde::Deserialize;
+use tracing::warn;
+use crate::observer::Data;
+
+///Marks binary snapshots, JSON state files written before the header existed start with `[`
+const MAGIC: &[u8; 4] = b"BLZS";
+///Bumped whenever the layout of the payload changes, older versions are migrated on load
+const VERSION: u16 = 1;
+///Magic, version, format, payload length and CRC32 of the payload
+const HEADER_LEN: usize = 4 + 2 + 1 + 8 + 4;
+
+pub type Entry = (PathBuf, Data);
+
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StateFormat {
+    ///Plain JSON array, readable but large
+    #[default]
+    Json,
+    ///Compact and fast, with a versioned header and checksum
+    Bincode,
+    ///Self-describing binary, with a versioned header and checksum
+    Cbor,
+}
+
+impl StateFormat {
+    fn extension(&self) -> &'static str {
+        match self {
+            StateFormat::Json => "json",
+            StateFormat::Bincode => "bin",
+            StateFormat::Cbor => "cbor",
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            StateFormat::Json => 0,
+            StateFormat::Bincode => 1,
+            StateFormat::Cbor => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Option<Self> {
+        [StateFormat::Json, StateFormat::Bincode, StateFormat::Cbor].into_iter().find(|format| format.id() == id)
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct StateOptions {
+    ///Where the cache is saved, `state.<json|bin|cbor>` next to the binary when not set
+    pub file: Option<PathBuf>,
+    pub format: StateFormat,
+    ///How many previous saves to keep as `<file>.1` (newest) to `<file>.N`
+    pub backups: usize,
+}
+
+impl Default for StateOptions {
+    fn default() -> Self {
+        Self {
+            file: None,
+            format: StateFormat::Json,
+            backups: 3,
+        }
+    }
+}
+
+impl StateOptions {
+    pub fn path(&self) -> PathBuf {
+        self.file.clone().unwrap_or_else(|| {
+            env::current_exe().unwrap().parent().unwrap().join(format!("state.{}", self.format.extension()))
+        })
+    }
+}
+
+///Saves the cache so that the file on disk is always either the old or the new state, never a partial one
+pub struct StateFile {
+    path: PathBuf,
+    format: StateFormat,
+    backups: usize,
+}
+
+impl StateFile {
+    pub fn new(options: StateOptions) -> Self {
+        Self {
+            path: options.path(),
+            format: options.format,
+            backups: options.backups,
+        }
+    }
+
+    pub fn save(&self, data: &[Entry]) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+
+        let tmp = with_suffix(&self.path, ".tmp");
+        let result = self.write(&tmp, data);
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp);
+            return result;
+        }
+
+        self.rotate()?;
+        fs::rename(&tmp, &self.path)?;
+        Self::sync_dir(&self.path)
+    }
+
+    ///Reads the newest readable save, falling back to backups when the file is damaged.
+    ///Any format or older version is accepted, it is written back in the configured one on the next save
+    pub fn load(&self) -> io::Result<Vec<Entry>> {
+        let candidates = std::iter::once(self.path.clone())
+            .chain((1..=self.backups).map(|i| with_suffix(&self.path, &format!(".{}", i))));
+
+        let mut first_error = None;
+        for path in candidates {
+            match fs::read(&path) {
+                Ok(bytes) => match decode(&bytes) {
+                    Ok(entries) => {
+                        if let Some(e) = &first_error {
+                            warn!(path = %path.display(), error = %e, "Restored state from a backup because the newer save is unreadable");
+                        }
+                        return Ok(entries);
+                    }
+                    Err(e) => {
+                        warn!(path = %path.display(), error = %e, "Skipping unreadable state file");
+                        first_error.get_or_insert(e);
+                    }
+                },
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => {
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn write(&self, path: &Path, data: &[Entry]) -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        match self.format {
+            StateFormat::Json => serde_json::to_writer(&mut writer, data)?,
+            format => {
+                let payload = encode(format, data)?;
+                writer.write_all(MAGIC)?;
+                writer.write_all(&VERSION.to_le_bytes())?;
+                writer.write_all(&[format.id()])?;
+                writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+                writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+                writer.write_all(&payload)?;
+            }
+        }
+        let file = writer.into_inner().map_err(|e| e.into_error())?;
+        file.sync_all()
+    }
+
+    ///Shifts `<file>.1..N-1` up by one and moves the current file to `<file>.1`
+    fn rotate(&self) -> io::Result<()> {
+        if self.backups == 0 || !self.path.exists() {
+            return Ok(());
+        }
+        for i in (1..self.backups).rev() {
+            let from = with_suffix(&self.path, &format!(".{}", i));
+            if from.exists() {
+                fs::rename(&from, with_suffix(&self.path, &format!(".{}", i + 1)))?;
+            }
+        }
+        fs::rename(&self.path, with_suffix(&self.path, ".1"))
+    }
+
+    ///Makes the renames durable, directories can't be opened for syncing on Windows
+    #[cfg(unix)]
+    fn sync_dir(path: &Path) -> io::Result<()> {
+        match path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            Some(dir) => File::open(dir)?.sync_all(),
+            None => File::open(".")?.sync_all(),
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(_path: &Path) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn encode(format: StateFormat, data: &[Entry]) -> io::Result<Vec<u8>> {
+    match format {
+        StateFormat::Json => Ok(serde_json::to_vec(data)?),
+        StateFormat::Bincode => bincode::serialize(data).map_err(invalid),
+        StateFormat::Cbor => {
+            let mut payload = vec![];
+            ciborium::into_writer(data, &mut payload).map_err(invalid)?;
+            Ok(payload)
+        }
+    }
+}
+
+fn decode(bytes: &[u8]) -> io::Result<Vec<Entry>> {
+    if !bytes.starts_with(MAGIC) {
+        // Version 0: plain JSON from before the header was introduced
+        return serde_json::from_slice(bytes).map_err(invalid);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(invalid("truncated header"));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    let format = StateFormat::from_id(bytes[6]).ok_or_else(|| invalid(format!("unknown format {}", bytes[6])))?;
+    let len = u64::from_le_bytes(bytes[7..15].try_into().unwrap()) as usize;
+    let checksum = u32::from_le_bytes(bytes[15..19].try_into().unwrap());
+    let payload = &bytes[HEADER_LEN..];
+    if payload.len() != len {
+        return Err(invalid(format!("expected {} bytes of data, found {}", len, payload.len())));
+    }
+    if crc32fast::hash(payload) != checksum {
+        return Err(invalid("checksum mismatch"));
+    }
+
+    match version {
+        1 => decode_payload(format, payload),
+        version if version > VERSION => Err(invalid(format!("version {} was written by a newer blazzy", version))),
+        version => Err(invalid(format!("unknown version {}", version))),
+    }
+}
+
+fn decode_payload<T: serde::de::DeserializeOwned>(format: StateFormat, payload: &[u8]) -> io::Result<T> {
+    match format {
+        StateFormat::Json => serde_json::from_slice(payload).map_err(invalid),
+        StateFormat::Bincode => bincode::deserialize(payload).map_err(invalid),
+        StateFormat::Cbor => ciborium::from_reader(payload).map_err(invalid),
+    }
+}
+
+fn invalid<E: Into<Box<dyn std::error::Error + Send + Sync>>>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::observer::{Action, Data};
+    use crate::state::{with_suffix, StateFile, StateFormat, StateOptions};
+
+    fn state_file(name: &str, format: StateFormat, backups: usize) -> (PathBuf, StateFile) {
+        let dir = std::env::temp_dir().join(format!("blazzy-state-{}-{}", std::process::id(), name));
+        let state = StateFile::new(StateOptions { file: Some(dir.join("state")), format, backups });
+        (dir, state)
+    }
+
+    fn entry(i: usize) -> Vec<(PathBuf, Data)> {
+        vec![(PathBuf::from(format!("/file{}", i)), Data::new(Action::Created, None))]
+    }
+
+    #[test]
+    fn rotate_test() {
+        let (dir, state) = state_file("rotate", StateFormat::Json, 2);
+        for i in 0..4 {
+            state.save(&entry(i)).unwrap();
+        }
+
+        let file = dir.join("state");
+        assert!(std::fs::read_to_string(&file).unwrap().contains("file3"));
+        assert!(std::fs::read_to_string(with_suffix(&file, ".1")).unwrap().contains("file2"));
+        assert!(std::fs::read_to_string(with_suffix(&file, ".2")).unwrap().contains("file1"));
+        assert!(!with_suffix(&file, ".3").exists());
+        assert!(!with_suffix(&file, ".tmp").exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn format_test() {
+        for format in [StateFormat::Json, StateFormat::Bincode, StateFormat::Cbor] {
+            let (dir, state) = state_file(&format!("{:?}", format), format, 1);
+            state.save(&entry(1)).unwrap();
+            assert_eq!(state.load().unwrap(), entry(1));
+
+            // A damaged save falls back to the backup
+            state.save(&entry(2)).unwrap();
+            let file = dir.join("state");
+            let mut bytes = std::fs::read(&file).unwrap();
+            let last = bytes.len() - 2;
+            bytes[last] ^= 0xff;
+            std::fs::write(&file, bytes).unwrap();
+            if format != StateFormat::Json {
+                assert_eq!(state.load().unwrap(), entry(1));
+            }
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+
+    #[test]
+    fn migrate_json_test() {
+        let (dir, state) = state_file("migrate", StateFormat::Bincode, 0);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("state"), serde_json::to_vec(&entry(7)).unwrap()).unwrap();
+        assert_eq!(state.load().unwrap(), entry(7));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
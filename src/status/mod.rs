@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use chrono::{DateTime, Local};
+use lazy_static::lazy_static;
+use serde::Serialize;
+use crate::auth::Scope;
+use crate::observer::Action;
+
+lazy_static! {
+    pub static ref SharedStatus: Status = Status::new();
+}
+
+///Counters and timestamps reported by `/status`
+pub struct Status {
+    started: Instant,
+    started_at: DateTime<Local>,
+    ready: AtomicBool,
+    clients: AtomicUsize,
+    events: Mutex<BTreeMap<String, u64>>,
+    last_autosave: Mutex<Option<DateTime<Local>>>,
+    ///Latest error of every watched path, and of the server itself under `None`
+    errors: Mutex<BTreeMap<Option<PathBuf>, LastError>>,
+    sequence: AtomicU64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LastError {
+    ///RFC 3339
+    pub time: String,
+    ///Watched path the error is about
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<PathBuf>,
+    pub message: String,
+    ///Orders errors of different paths
+    #[serde(skip)]
+    sequence: u64,
+}
+
+impl Status {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            started_at: Local::now(),
+            ready: AtomicBool::new(false),
+            clients: AtomicUsize::new(0),
+            events: Mutex::new(BTreeMap::new()),
+            last_autosave: Mutex::new(None),
+            errors: Mutex::new(BTreeMap::new()),
+            sequence: AtomicU64::new(0),
+        }
+    }
+
+    ///State is restored and the observers are started
+    pub fn set_ready(&self) {
+        self.ready.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.ready.load(Ordering::SeqCst)
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn started_at(&self) -> String {
+        self.started_at.to_rfc3339()
+    }
+
+    pub fn event(&self, action: Action) {
+        *self.events.lock().unwrap().entry(String::from(action)).or_default() += 1;
+    }
+
+    pub fn events(&self) -> BTreeMap<String, u64> {
+        self.events.lock().unwrap().clone()
+    }
+
+    pub fn client_connected(&self) {
+        self.clients.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn client_disconnected(&self) {
+        self.clients.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn clients(&self) -> usize {
+        self.clients.load(Ordering::Relaxed)
+    }
+
+    pub fn saved(&self) {
+        *self.last_autosave.lock().unwrap() = Some(Local::now());
+    }
+
+    pub fn last_autosave(&self) -> Option<String> {
+        self.last_autosave.lock().unwrap().map(|time| time.to_rfc3339())
+    }
+
+    ///Error of the server itself, only shown to tokens that see every path
+    pub fn error(&self, message: impl Into<String>) {
+        self.record(None, message.into());
+    }
+
+    pub fn watch_error(&self, path: &Path, message: impl Into<String>) {
+        self.record(Some(path.to_path_buf()), message.into());
+    }
+
+    fn record(&self, path: Option<PathBuf>, message: String) {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        let error = LastError { time: Local::now().to_rfc3339(), path: path.clone(), message, sequence };
+        self.errors.lock().unwrap().insert(path, error);
+    }
+
+    ///Latest error visible to `scope`
+    pub fn last_error(&self, scope: &Scope) -> Option<LastError> {
+        self.errors.lock().unwrap().values()
+            .filter(|error| match &error.path {
+                Some(path) => scope.allows(path),
+                None => scope.prefixes.is_empty(),
+            })
+            .max_by_key(|error| error.sequence)
+            .cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+    use crate::auth::{Access, Scope};
+    use crate::status::Status;
+
+    #[test]
+    fn last_error_test() {
+        let status = Status::new();
+        status.watch_error(Path::new("/srv/a"), "a failed");
+        status.error("failed to save state");
+        status.watch_error(Path::new("/srv/b"), "b failed");
+
+        assert_eq!(status.last_error(&Scope::full()).unwrap().message, "b failed");
+        let scope = Scope { access: Access::Read, prefixes: vec![PathBuf::from("/srv/a")] };
+        assert_eq!(status.last_error(&scope).unwrap().message, "a failed");
+        let scope = Scope { access: Access::Read, prefixes: vec![PathBuf::from("/etc")] };
+        assert!(status.last_error(&scope).is_none());
+    }
+}
@@ -0,0 +1,365 @@
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use chrono::{DateTime, Local, TimeZone};
+use lru::LruCache;
+use rusqlite::{params, Connection, OptionalExtension};
+use tracing::{error, warn};
+pub use blazzy_client::models::Event;
+use crate::auth::Scope;
+use crate::config::Retention;
+use crate::duration::HumanDuration;
+use crate::metrics;
+use crate::observer::{Action, Data};
+
+///Deletes are done in batches of this many inserts, so retention doesn't slow down every event
+const PRUNE_EVERY: u64 = 1000;
+
+///Where the cache keeps events, owned by the `AsyncCacher` task
+pub trait Store: Send {
+    fn put(&mut self, path: PathBuf, data: Data);
+    ///Latest event of every path
+    fn latest(&mut self) -> Result<HashMap<PathBuf, Data>, String>;
+    ///Removes the least recently changed path
+    fn pop_oldest(&mut self) -> Result<Option<(PathBuf, Data)>, String>;
+    fn is_empty(&mut self) -> Result<bool, String>;
+    ///Paths in memory, events in a database
+    fn len(&mut self) -> Result<usize, String>;
+    fn set_retention(&mut self, retention: &Retention);
+    ///False when `history` can only return an error
+    fn keeps_history(&self) -> bool;
+    fn history(&mut self, query: &HistoryQuery) -> Result<Vec<Event>, String>;
+}
+
+///Latest event per path, least recently changed paths are evicted above `max_entries`
+pub struct MemoryStore {
+    map: LruCache<PathBuf, Data>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self { map: LruCache::unbounded() }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Store for MemoryStore {
+    fn put(&mut self, path: PathBuf, data: Data) {
+        if self.map.push(path.clone(), data).is_some_and(|(evicted, _)| evicted != path) {
+            metrics::EVICTED.inc();
+        }
+    }
+
+    fn latest(&mut self) -> Result<HashMap<PathBuf, Data>, String> {
+        Ok(self.map.iter().map(|(k, v)| (k.clone(), v.clone())).collect())
+    }
+
+    fn pop_oldest(&mut self) -> Result<Option<(PathBuf, Data)>, String> {
+        Ok(self.map.pop_lru())
+    }
+
+    fn is_empty(&mut self) -> Result<bool, String> {
+        Ok(self.map.is_empty())
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.map.len())
+    }
+
+    fn set_retention(&mut self, retention: &Retention) {
+        if let Some(max) = retention.max_entries.and_then(NonZeroUsize::new) {
+            let before = self.map.len();
+            self.map.resize(max);
+            metrics::EVICTED.inc_by((before - self.map.len()) as u64);
+        }
+    }
+
+    fn keeps_history(&self) -> bool {
+        false
+    }
+
+    fn history(&mut self, _query: &HistoryQuery) -> Result<Vec<Event>, String> {
+        Err("history needs `storage.backend = \"sqlite\"`".to_string())
+    }
+}
+
+///Every event with the time it was recorded, indexed by path, action and time.
+///`latest` holds the id of the newest event of every path, kept up to date by triggers
+pub struct SqliteStore {
+    connection: Connection,
+    max_entries: Option<usize>,
+    max_age: Option<Duration>,
+    inserts: u64,
+    ///Rows in `events`, counted once on open
+    count: usize,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> rusqlite::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            let _ = std::fs::create_dir_all(dir);
+        }
+        let connection = Connection::open(path)?;
+        connection.execute_batch("
+            PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = NORMAL;
+            CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                path TEXT NOT NULL,
+                action TEXT NOT NULL,
+                time INTEGER NOT NULL,
+                data TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS events_path ON events (path, id);
+            CREATE INDEX IF NOT EXISTS events_action ON events (action, time);
+            CREATE INDEX IF NOT EXISTS events_time ON events (time);
+            CREATE TABLE IF NOT EXISTS latest (
+                path TEXT PRIMARY KEY,
+                id INTEGER NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS latest_id ON latest (id);
+            CREATE TRIGGER IF NOT EXISTS latest_insert AFTER INSERT ON events BEGIN
+                INSERT INTO latest (path, id) VALUES (new.path, new.id)
+                    ON CONFLICT (path) DO UPDATE SET id = excluded.id;
+            END;
+            CREATE TRIGGER IF NOT EXISTS latest_delete AFTER DELETE ON events BEGIN
+                DELETE FROM latest WHERE path = old.path AND id = old.id;
+            END;
+        ")?;
+        // Databases from before the `latest` table
+        if !connection.query_row("SELECT EXISTS (SELECT 1 FROM latest)", [], |row| row.get::<_, bool>(0))? {
+            connection.execute("INSERT INTO latest (path, id) SELECT path, MAX(id) FROM events GROUP BY path", [])?;
+        }
+        let count = connection.query_row("SELECT COUNT(*) FROM events", [], |row| row.get::<_, i64>(0))? as usize;
+        Ok(Self { connection, max_entries: None, max_age: None, inserts: 0, count })
+    }
+
+    fn prune(&mut self) -> rusqlite::Result<()> {
+        if let Some(max_age) = self.max_age {
+            let oldest = now_millis() - max_age.as_millis() as i64;
+            let pruned = self.connection.execute("DELETE FROM events WHERE time < ?1", params![oldest])?;
+            self.count -= pruned;
+            metrics::EVICTED.inc_by(pruned as u64);
+        }
+        if let Some(max_entries) = self.max_entries {
+            let pruned = self.connection.execute(
+                "DELETE FROM events WHERE id <= (SELECT MAX(id) FROM events) - ?1",
+                params![max_entries as i64],
+            )?;
+            self.count -= pruned;
+            metrics::EVICTED.inc_by(pruned as u64);
+        }
+        Ok(())
+    }
+
+    fn row(row: &rusqlite::Row) -> rusqlite::Result<(PathBuf, Data)> {
+        let path: String = row.get(0)?;
+        let data: String = row.get(1)?;
+        let data = serde_json::from_str(&data)
+            .map_err(|e| rusqlite::Error::FromSqlConversionFailure(1, rusqlite::types::Type::Text, Box::new(e)))?;
+        Ok((PathBuf::from(path), data))
+    }
+}
+
+impl Store for SqliteStore {
+    fn put(&mut self, path: PathBuf, data: Data) {
+        let result = self.connection.execute(
+            "INSERT INTO events (path, action, time, data) VALUES (?1, ?2, ?3, ?4)",
+            params![path.to_string_lossy(), String::from(data.action()), now_millis(), serde_json::to_string(&data).unwrap()],
+        );
+        match result {
+            Ok(inserted) => self.count += inserted,
+            Err(e) => error!(path = %path.display(), error = %e, "Failed to store event"),
+        }
+
+        self.inserts += 1;
+        if self.inserts.is_multiple_of(PRUNE_EVERY) {
+            if let Err(e) = self.prune() {
+                error!(error = %e, "Failed to apply retention");
+            }
+        }
+    }
+
+    fn latest(&mut self) -> Result<HashMap<PathBuf, Data>, String> {
+        let mut statement = self.connection.prepare_cached(
+            "SELECT events.path, events.data FROM latest JOIN events ON events.id = latest.id"
+        ).map_err(|e| e.to_string())?;
+        let rows = statement.query_map([], Self::row).map_err(|e| e.to_string())?;
+        Ok(rows.filter_map(|row| row.map_err(|e| warn!(error = %e, "Skipping stored event")).ok()).collect())
+    }
+
+    fn pop_oldest(&mut self) -> Result<Option<(PathBuf, Data)>, String> {
+        let oldest = self.connection.query_row(
+            "SELECT events.path, events.data FROM latest JOIN events ON events.id = latest.id ORDER BY latest.id LIMIT 1",
+            [],
+            Self::row,
+        ).optional().map_err(|e| e.to_string())?;
+        let Some(oldest) = oldest else { return Ok(None) };
+        let deleted = self.connection.execute("DELETE FROM events WHERE path = ?1", params![oldest.0.to_string_lossy()]).map_err(|e| e.to_string())?;
+        self.count -= deleted;
+        Ok(Some(oldest))
+    }
+
+    fn is_empty(&mut self) -> Result<bool, String> {
+        Ok(self.count == 0)
+    }
+
+    fn len(&mut self) -> Result<usize, String> {
+        Ok(self.count)
+    }
+
+    fn set_retention(&mut self, retention: &Retention) {
+        self.max_entries = retention.max_entries;
+        self.max_age = retention.max_age.map(Duration::from);
+        if let Err(e) = self.prune() {
+            error!(error = %e, "Failed to apply retention");
+        }
+    }
+
+    fn keeps_history(&self) -> bool {
+        true
+    }
+
+    fn history(&mut self, query: &HistoryQuery) -> Result<Vec<Event>, String> {
+        let mut sql = "SELECT path, data, id, time FROM events WHERE 1 = 1".to_string();
+        let mut values: Vec<Box<dyn rusqlite::ToSql>> = vec![];
+        if let Some(path) = &query.path {
+            let prefix = path.to_string_lossy().to_string();
+            sql += &format!(" AND path >= ?{} AND path < ?{}", values.len() + 1, values.len() + 2);
+            values.push(Box::new(prefix.clone()));
+            values.push(Box::new(prefix + "\u{10FFFF}"));
+        }
+        if let Some(action) = query.action {
+            sql += &format!(" AND action = ?{}", values.len() + 1);
+            values.push(Box::new(String::from(action)));
+        }
+        if let Some(since) = query.since {
+            sql += &format!(" AND time >= ?{}", values.len() + 1);
+            values.push(Box::new(since));
+        }
+        if let Some(until) = query.until {
+            sql += &format!(" AND time < ?{}", values.len() + 1);
+            values.push(Box::new(until));
+        }
+        if let Some(before) = query.before {
+            sql += &format!(" AND id < ?{}", values.len() + 1);
+            values.push(Box::new(before));
+        }
+        sql += " ORDER BY id DESC";
+
+        let mut statement = self.connection.prepare(&sql).map_err(|e| e.to_string())?;
+        let rows = statement.query_map(rusqlite::params_from_iter(values.iter()), |row| {
+            let (path, data) = Self::row(row)?;
+            let time = Local.timestamp_millis_opt(row.get(3)?).single()
+                .ok_or_else(|| rusqlite::Error::FromSqlConversionFailure(3, rusqlite::types::Type::Integer, "time is out of range".into()))?;
+            Ok(Event { id: row.get(2)?, path, time: time.to_rfc3339(), data })
+        }).map_err(|e| e.to_string())?;
+
+        let mut events = vec![];
+        for event in rows {
+            let event = event.map_err(|e| e.to_string())?;
+            // Prefix ranges are textual, scopes compare whole path components
+            if query.path.as_ref().is_some_and(|path| !event.path.starts_with(path)) || !query.scope.allows(&event.path) {
+                continue;
+            }
+            events.push(event);
+            if events.len() >= query.limit {
+                break;
+            }
+        }
+        Ok(events)
+    }
+}
+
+///Start from `HistoryQuery::default()`, which matches the 100 newest events, and set the fields to narrow it
+#[derive(Debug, Clone)]
+pub struct HistoryQuery {
+    ///Only events under this path
+    pub path: Option<PathBuf>,
+    pub action: Option<Action>,
+    ///Unix time in milliseconds
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    ///Events with a lower id, for paging
+    pub before: Option<i64>,
+    pub limit: usize,
+    pub(crate) scope: Scope,
+}
+
+impl Default for HistoryQuery {
+    fn default() -> Self {
+        Self { path: None, action: None, since: None, until: None, before: None, limit: 100, scope: Scope::full() }
+    }
+}
+
+pub fn now_millis() -> i64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64
+}
+
+///Unix milliseconds from an RFC 3339 time or a duration ago
+pub fn parse_time(s: &str) -> Result<i64, String> {
+    match DateTime::parse_from_rfc3339(s) {
+        Ok(time) => Ok(time.timestamp_millis()),
+        Err(_) => {
+            let ago = s.parse::<HumanDuration>().map_err(|_| format!("`{}` is neither an RFC 3339 time nor a duration", s))?;
+            Ok(now_millis() - ago.0.as_millis() as i64)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::auth::Scope;
+    use crate::config::Retention;
+    use crate::observer::{Action, Data};
+    use crate::storage::{HistoryQuery, SqliteStore, Store};
+
+    #[test]
+    fn sqlite_test() {
+        let path = std::env::temp_dir().join(format!("blazzy-{}.db", std::process::id()));
+        let mut store = SqliteStore::open(&path).unwrap();
+        store.put(PathBuf::from("/srv/a"), Data::new(Action::Created, None));
+        store.put(PathBuf::from("/srv/b"), Data::new(Action::Created, None));
+        store.put(PathBuf::from("/srv/a"), Data::new(Action::Modified, None));
+        store.put(PathBuf::from("/srv2/c"), Data::new(Action::Modified, None));
+
+        assert_eq!(store.latest().unwrap().len(), 3);
+        assert_eq!(store.latest().unwrap()[&PathBuf::from("/srv/a")].action(), Action::Modified);
+
+        let query = HistoryQuery {
+            path: Some(PathBuf::from("/srv")),
+            action: Some(Action::Modified),
+            limit: 10,
+            ..HistoryQuery::default()
+        };
+        let events = store.history(&query).unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].path, PathBuf::from("/srv/a"));
+
+        assert_eq!(store.len().unwrap(), 4);
+        assert_eq!(store.pop_oldest().unwrap().unwrap().0, PathBuf::from("/srv/b"));
+        assert_eq!(store.len().unwrap(), 3);
+        store.set_retention(&Retention { max_entries: Some(1), max_age: None });
+        assert_eq!(store.latest().unwrap().len(), 1);
+        assert_eq!(store.len().unwrap(), 1);
+
+        drop(store);
+        let mut store = SqliteStore::open(&path).unwrap();
+        assert_eq!(store.len().unwrap(), 1);
+        assert_eq!(store.latest().unwrap()[&PathBuf::from("/srv2/c")].action(), Action::Modified);
+        store.connection.execute("UPDATE events SET time = ?1", [i64::MAX]).unwrap();
+        assert!(store.history(&HistoryQuery::default()).is_err());
+
+        drop(store);
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+}
@@ -0,0 +1,144 @@
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use blazzy_client::{Client, Update};
+use chrono::Local;
+use clap::{Args, ValueEnum};
+use futures::StreamExt;
+use serde_json::json;
+use crate::observer::{Action, Data};
+
+#[derive(ValueEnum, Debug, Clone, Copy, Default, PartialEq)]
+pub enum TailFormat {
+    ///Time, action and path, aligned
+    #[default]
+    Human,
+    ///One JSON object per line with time, path, action and metadata
+    Json,
+    ///--template with the placeholders replaced
+    Template,
+}
+
+#[derive(Args, Debug, Clone)]
+pub struct TailArgs {
+    ///Server to connect to, http(s)://host:port or unix:/path/to.sock
+    #[arg(long, default_value = "http://127.0.0.1:8080")]
+    pub url: String,
+    ///Token, when the server requires one
+    #[arg(long)]
+    pub token: Option<String>,
+    ///Only changes under this path
+    #[arg(long)]
+    pub path: Option<PathBuf>,
+    ///Only paths matching this glob, repeatable
+    #[arg(long)]
+    pub include: Vec<String>,
+    ///Drop paths matching this glob, repeatable
+    #[arg(long)]
+    pub exclude: Vec<String>,
+    ///Only changes with this action, repeatable
+    #[arg(long)]
+    pub action: Vec<Action>,
+    ///First print the events recorded since this RFC 3339 time or duration before now like 10m (SQLite backend)
+    #[arg(long)]
+    pub since: Option<String>,
+    #[arg(long, value_enum, default_value = "human")]
+    pub format: TailFormat,
+    ///Line printed per change for --format template: {time}, {action}, {path}, {type}, {size} and {modified}
+    #[arg(long, required_if_eq("format", "template"))]
+    pub template: Option<String>,
+    ///Exit when the connection is lost instead of reconnecting
+    #[arg(long)]
+    pub no_reconnect: bool,
+}
+
+///Prints the changes of a running server until interrupted, status messages go to stderr
+pub async fn run(args: TailArgs) -> Result<(), String> {
+    let mut client = Client::new(&args.url).map_err(|e| e.to_string())?;
+    if let Some(token) = &args.token {
+        client = client.token(token);
+    }
+    // Without history the server could only answer every reconnection with an error
+    let resume = args.since.is_some() || client.keeps_history().await.unwrap_or(false);
+    let mut subscriber = client.subscribe().resume(resume);
+    if let Some(path) = &args.path {
+        subscriber = subscriber.path(path);
+    }
+    for glob in &args.include {
+        subscriber = subscriber.include(glob);
+    }
+    for glob in &args.exclude {
+        subscriber = subscriber.exclude(glob);
+    }
+    if let Some(since) = &args.since {
+        subscriber = subscriber.since(since);
+    }
+
+    let mut updates = subscriber.stream();
+    let mut stdout = std::io::stdout().lock();
+    let mut connected = false;
+    while let Some(update) = updates.next().await {
+        match update {
+            Update::Changed { path, data } => {
+                if !args.action.is_empty() && !args.action.contains(&data.action()) {
+                    continue;
+                }
+                // Stops quietly when piped into a command that exits, like head
+                if writeln!(stdout, "{}", format(&args, &path, &data)).is_err() || stdout.flush().is_err() {
+                    return Ok(());
+                }
+            }
+            Update::Connected if connected => eprintln!("Connected again"),
+            Update::Connected => connected = true,
+            Update::Resumed { events, complete: true } => eprintln!("Replaying {} events", events),
+            Update::Resumed { events, complete: false } => eprintln!("Replaying the last {} events, older ones were skipped", events),
+            Update::ResumeFailed(e) => eprintln!("Can't replay events: {}", e),
+            Update::Overflow(overflow) => eprintln!("Missed {} changes, {} merged, tail fell behind", overflow.dropped, overflow.coalesced),
+            Update::Failed(error) => eprintln!("Observer of {} failed: {}, restarting in {}", error.path.display(), error.message, error.retry_in),
+            Update::Disconnected { reason, .. } if args.no_reconnect => return Err(format!("disconnected: {}", reason)),
+            Update::Disconnected { reason, retry_in } => eprintln!("Disconnected: {}, reconnecting in {:?}", reason, retry_in),
+            Update::Rejected { status, reason } => return Err(format!("server refused the subscription ({}): {}", status, reason)),
+        }
+    }
+    Ok(())
+}
+
+///One line for a change, the time is when it was received
+fn format(args: &TailArgs, path: &Path, data: &Data) -> String {
+    let time = Local::now();
+    match args.format {
+        TailFormat::Human => format!("{}  {:<10}  {}", time.format("%Y-%m-%d %H:%M:%S%.3f"), String::from(data.action()), path.display()),
+        TailFormat::Json => json!({
+            "time": time.to_rfc3339(),
+            "path": path,
+            "action": data.action(),
+            "metadata": data.metadata(),
+        }).to_string(),
+        TailFormat::Template => {
+            let metadata = data.metadata();
+            args.template.as_deref().unwrap_or_default()
+                .replace("{time}", &time.to_rfc3339())
+                .replace("{action}", &String::from(data.action()))
+                .replace("{path}", &path.display().to_string())
+                .replace("{type}", metadata.map(|m| m.file_type.as_str()).unwrap_or("-"))
+                .replace("{size}", &metadata.map(|m| m.len_in_bytes.to_string()).unwrap_or("-".to_string()))
+                .replace("{modified}", metadata.map(|m| m.modified.as_str()).unwrap_or("-"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use clap::Parser;
+    use crate::cli::{Command, CLI};
+    use crate::observer::{Action, Data};
+    use crate::tail::format;
+
+    #[test]
+    fn template_test() {
+        let cli = CLI::parse_from(["blazzy", "tail", "--format", "template", "--template", "{action} {path} {size}"]);
+        let Some(Command::Tail(args)) = cli.get_command() else { panic!("not a tail command") };
+        assert_eq!(format(&args, Path::new("/srv/a"), &Data::new(Action::Created, None)), "Created /srv/a -");
+        assert!(CLI::try_parse_from(["blazzy", "tail", "--format", "template"]).is_err());
+    }
+}
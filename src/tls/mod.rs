@@ -0,0 +1,63 @@
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use rustls::ServerConfig;
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+
+///Serves the certificate loaded from disk and swaps it in place on reload,
+///so existing listeners pick up renewed certificates without rebinding
+#[derive(Debug)]
+pub struct CertResolver {
+    cert_path: PathBuf,
+    key_path: PathBuf,
+    key: RwLock<Arc<CertifiedKey>>,
+}
+
+impl CertResolver {
+    pub fn load(cert_path: PathBuf, key_path: PathBuf) -> io::Result<Self> {
+        let key = Self::read_key(&cert_path, &key_path)?;
+        Ok(Self {
+            cert_path,
+            key_path,
+            key: RwLock::new(Arc::new(key)),
+        })
+    }
+
+    pub fn reload(&self) -> io::Result<()> {
+        let key = Self::read_key(&self.cert_path, &self.key_path)?;
+        *self.key.write().unwrap() = Arc::new(key);
+        Ok(())
+    }
+
+    pub fn server_config(self: &Arc<Self>) -> ServerConfig {
+        ServerConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_no_client_auth()
+            .with_cert_resolver(self.clone())
+    }
+
+    fn read_key(cert_path: &Path, key_path: &Path) -> io::Result<CertifiedKey> {
+        let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert_path)?))
+            .collect::<Result<Vec<_>, _>>()?;
+        if certs.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("No certificates found in {}", cert_path.display())));
+        }
+
+        let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key_path)?))?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("No private key found in {}", key_path.display())))?;
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        Ok(CertifiedKey::new(certs, key))
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, _: ClientHello) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
@@ -0,0 +1,165 @@
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use serde::Deserialize;
+use tracing::warn;
+use crate::observer::Data;
+use crate::state::StateOptions;
+
+///Record length and CRC32 of the record
+const RECORD_HEADER_LEN: usize = 4 + 4;
+
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct WalOptions {
+    pub enabled: bool,
+    ///`<state file>.wal` when not set
+    pub file: Option<PathBuf>,
+    ///fsync after every event, survives power loss but is much slower
+    pub sync: bool,
+}
+
+impl WalOptions {
+    pub fn path(&self, state: &StateOptions) -> PathBuf {
+        self.file.clone().unwrap_or_else(|| {
+            let mut name = OsString::from(state.path().as_os_str());
+            name.push(".wal");
+            PathBuf::from(name)
+        })
+    }
+}
+
+///Append-only log of the events that reached the cache since the last checkpoint.
+///
+///A checkpoint moves the log aside to `<file>.old` while the snapshot is taken, so events arriving during the save go
+///to a fresh log, and removes it once the snapshot is on disk. Both files are replayed on startup.
+pub struct Wal {
+    path: PathBuf,
+    file: File,
+    sync: bool,
+}
+
+impl Wal {
+    pub fn open(path: PathBuf, sync: bool) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok(Self { path, file, sync })
+    }
+
+    ///Events from an unfinished checkpoint first, then the current log, a torn last record is dropped
+    pub fn replay(&self) -> io::Result<Vec<(PathBuf, Data)>> {
+        let mut entries = vec![];
+        for path in [self.old_path(), self.path.clone()] {
+            match fs::read(&path) {
+                Ok(bytes) => entries.extend(Self::decode(&path, &bytes)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(entries)
+    }
+
+    pub fn append(&mut self, path: &Path, data: &Data) -> io::Result<()> {
+        let payload = bincode::serialize(&(path, data)).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let mut record = Vec::with_capacity(RECORD_HEADER_LEN + payload.len());
+        record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        record.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        record.extend_from_slice(&payload);
+        self.file.write_all(&record)?;
+        if self.sync {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    ///Starts a checkpoint, everything logged so far is covered by the snapshot taken right after this call
+    pub fn rotate(&mut self) -> io::Result<()> {
+        let old = self.old_path();
+        if old.exists() {
+            // The previous checkpoint was not saved, keep its events in front of the current ones
+            let mut old_file = OpenOptions::new().append(true).open(&old)?;
+            old_file.write_all(&fs::read(&self.path)?)?;
+            old_file.sync_all()?;
+            self.file.set_len(0)?;
+        } else {
+            self.file.sync_all()?;
+            fs::rename(&self.path, &old)?;
+            self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        }
+        Ok(())
+    }
+
+    ///The snapshot is on disk, the events before it are no longer needed
+    pub fn truncate(&mut self) -> io::Result<()> {
+        match fs::remove_file(self.old_path()) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn old_path(&self) -> PathBuf {
+        let mut name = OsString::from(self.path.as_os_str());
+        name.push(".old");
+        PathBuf::from(name)
+    }
+
+    fn decode(path: &Path, mut bytes: &[u8]) -> Vec<(PathBuf, Data)> {
+        let mut entries = vec![];
+        while !bytes.is_empty() {
+            let record = (bytes.len() >= RECORD_HEADER_LEN).then(|| {
+                let len = u32::from_le_bytes(bytes[0..4].try_into().unwrap()) as usize;
+                let checksum = u32::from_le_bytes(bytes[4..8].try_into().unwrap());
+                let payload = bytes.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+                if crc32fast::hash(payload) != checksum {
+                    return None;
+                }
+                bincode::deserialize(payload).ok().map(|entry| (entry, RECORD_HEADER_LEN + len))
+            }).flatten();
+
+            match record {
+                Some((entry, len)) => {
+                    entries.push(entry);
+                    bytes = &bytes[len..];
+                }
+                None => {
+                    warn!(path = %path.display(), bytes = bytes.len(), "Dropping unreadable bytes at the end of the WAL");
+                    break;
+                }
+            }
+        }
+        entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::path::PathBuf;
+    use crate::observer::{Action, Data};
+    use crate::wal::Wal;
+
+    #[test]
+    fn checkpoint_test() {
+        let dir = std::env::temp_dir().join(format!("blazzy-wal-{}", std::process::id()));
+        let path = dir.join("state.wal");
+        let mut wal = Wal::open(path.clone(), false).unwrap();
+
+        wal.append(&PathBuf::from("/a"), &Data::new(Action::Created, None)).unwrap();
+        wal.rotate().unwrap();
+        wal.append(&PathBuf::from("/b"), &Data::new(Action::Modified, None)).unwrap();
+        // Crash before the snapshot was saved: both files are replayed in order
+        assert_eq!(wal.replay().unwrap().len(), 2);
+
+        wal.truncate().unwrap();
+        let entries = wal.replay().unwrap();
+        assert_eq!(entries, vec![(PathBuf::from("/b"), Data::new(Action::Modified, None))]);
+
+        // Torn write at the end of the log
+        std::fs::OpenOptions::new().append(true).open(&path).unwrap().write_all(&[42, 0, 0]).unwrap();
+        assert_eq!(Wal::open(path, false).unwrap().replay().unwrap(), entries);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
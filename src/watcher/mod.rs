@@ -0,0 +1,273 @@
+use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use futures::StreamExt;
+use futures::stream::BoxStream;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+use crate::async_cacher::AsyncCacher;
+use crate::config::{Backend, Retention, Root};
+use crate::duration::HumanDuration;
+use crate::event_bus::{EventBus, SharedEventBus};
+use crate::filter::{Filter, Patterns};
+use crate::observer::Data;
+use crate::storage::{Event, HistoryQuery, SqliteStore};
+use crate::watches::{SharedWatches, WatchError, WatchInfo, WatchManager};
+
+///Events kept for each `events()` stream before a slow consumer starts missing them
+const DEFAULT_CAPACITY: usize = 4096;
+
+///What an `events()` stream yields
+#[derive(Debug, Clone)]
+pub enum WatchEvent {
+    Changed { path: PathBuf, data: Data },
+    ///An observer failed and is restarted after `retry_in`
+    Failed(WatchError),
+    ///The stream fell behind and skipped this many changes
+    Missed(u64),
+}
+
+#[derive(Debug)]
+pub enum BuildError {
+    NoRoots,
+    Filter(globset::Error),
+    Storage(PathBuf, rusqlite::Error),
+}
+
+impl Display for BuildError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildError::NoRoots => write!(f, "no roots to observe"),
+            BuildError::Filter(e) => write!(f, "invalid filter: {}", e),
+            BuildError::Storage(path, e) => write!(f, "failed to open database {}: {}", path.display(), e),
+        }
+    }
+}
+
+impl std::error::Error for BuildError {}
+
+///Configures a `Watcher`, see `Watcher::builder`
+#[derive(Default)]
+pub struct WatcherBuilder {
+    roots: Vec<Root>,
+    patterns: Patterns,
+    backend: Backend,
+    database: Option<PathBuf>,
+    retention: Retention,
+    capacity: Option<usize>,
+}
+
+impl WatcherBuilder {
+    ///Observes `path` recursively, repeat to observe several
+    pub fn root(mut self, path: impl Into<PathBuf>) -> Self {
+        self.roots.push(Root::new(path.into()));
+        self
+    }
+
+    ///Observes `path` with its own filter, applied in addition to `include` and `exclude`
+    pub fn root_with_filter(mut self, path: impl Into<PathBuf>, filter: Filter) -> Self {
+        self.roots.push(Root { path: path.into(), filters: filter });
+        self
+    }
+
+    ///Only keep paths matching this glob
+    pub fn include(mut self, glob: impl Into<String>) -> Self {
+        self.patterns.include.push(glob.into());
+        self
+    }
+
+    ///Drop paths matching this glob
+    pub fn exclude(mut self, glob: impl Into<String>) -> Self {
+        self.patterns.exclude.push(glob.into());
+        self
+    }
+
+    ///Keep every event in an SQLite database at `path` instead of the latest event per path in memory
+    pub fn sqlite(mut self, path: impl Into<PathBuf>) -> Self {
+        self.backend = Backend::Sqlite;
+        self.database = Some(path.into());
+        self
+    }
+
+    ///Memory: evict the least recently changed paths above this many entries. SQLite: keep this many events
+    pub fn max_entries(mut self, max_entries: usize) -> Self {
+        self.retention.max_entries = Some(max_entries);
+        self
+    }
+
+    ///SQLite only: delete events older than this
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.retention.max_age = Some(HumanDuration(max_age));
+        self
+    }
+
+    ///Changes buffered for each `events()` stream
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = Some(capacity);
+        self
+    }
+
+    ///Starts observing, must be called within a Tokio runtime
+    pub fn start(self) -> Result<Watcher, BuildError> {
+        if self.roots.is_empty() {
+            return Err(BuildError::NoRoots);
+        }
+        let filter = Filter::new(self.patterns).map_err(BuildError::Filter)?;
+
+        let cacher = Arc::new(AsyncCacher::init());
+        if self.backend == Backend::Sqlite {
+            let path = self.database.unwrap_or_else(|| PathBuf::from("blazzy.db"));
+            let store = SqliteStore::open(&path).map_err(|e| BuildError::Storage(path, e))?;
+            cacher.set_store(Box::new(store));
+        }
+        cacher.set_retention(self.retention);
+
+        let bus = Arc::new(EventBus::init(self.capacity.unwrap_or(DEFAULT_CAPACITY)));
+        let (sender, mut receiver) = tokio::sync::mpsc::unbounded_channel::<(PathBuf, Data)>();
+        let watches = Arc::new(Mutex::new(WatchManager::new(Arc::new(sender))));
+        watches.lock().unwrap().apply(&self.roots, &filter);
+
+        let pump_cacher = cacher.clone();
+        let pump_bus = bus.clone();
+        let (stop, mut stopped) = oneshot::channel::<()>();
+        let pump = tokio::task::spawn(async move {
+            let record = |(path, data): (PathBuf, Data)| {
+                pump_cacher.put(path.clone(), data.clone());
+                pump_bus.publish(path, data);
+            };
+            loop {
+                tokio::select! {
+                    biased;
+                    Some(change) = receiver.recv() => record(change),
+                    _ = &mut stopped => break,
+                }
+            }
+            // Sent before the observers stopped
+            while let Ok(change) = receiver.try_recv() {
+                record(change);
+            }
+        });
+
+        Ok(Watcher { watches, cache: Cache { cacher }, bus, stop: Some(stop), pump: Some(pump) })
+    }
+}
+
+///Observes roots and keeps their changes in a cache, without the HTTP server
+pub struct Watcher {
+    watches: SharedWatches,
+    cache: Cache,
+    bus: Arc<EventBus>,
+    stop: Option<oneshot::Sender<()>>,
+    pump: Option<JoinHandle<()>>,
+}
+
+impl Watcher {
+    pub fn builder() -> WatcherBuilder {
+        WatcherBuilder::default()
+    }
+
+    ///Changes from now on, every stream gets every change. Ends once the watcher is stopped
+    pub fn events(&self) -> BoxStream<'static, WatchEvent> {
+        let watches = self.watches.clone();
+        let receivers = (self.bus.subscribe(), SharedEventBus.subscribe_errors());
+        futures::stream::unfold(receivers, move |(mut events, mut errors)| {
+            let watches = watches.clone();
+            async move {
+                loop {
+                    let event = tokio::select! {
+                        event = events.recv() => match event {
+                            Ok((path, data)) => WatchEvent::Changed { path, data },
+                            Err(RecvError::Lagged(n)) => WatchEvent::Missed(n),
+                            Err(RecvError::Closed) => return None,
+                        },
+                        Ok(error) = errors.recv() => {
+                            // Failures are reported process wide, keep those of this watcher's roots
+                            if !watches.lock().unwrap().list().iter().any(|watch| watch.path == error.path) {
+                                continue;
+                            }
+                            WatchEvent::Failed(error)
+                        }
+                    };
+                    return Some((event, (events, errors)));
+                }
+            }
+        }).boxed()
+    }
+
+    pub fn cache(&self) -> Cache {
+        self.cache.clone()
+    }
+
+    pub fn watches(&self) -> Vec<WatchInfo> {
+        self.watches.lock().unwrap().list()
+    }
+
+    ///Starts observing another root, `None` when it is already observed
+    pub fn add_root(&self, path: impl Into<PathBuf>, filter: Filter) -> Option<WatchInfo> {
+        self.watches.lock().unwrap().add(path.into(), filter)
+    }
+
+    pub fn remove_root(&self, id: u64) -> Option<WatchInfo> {
+        self.watches.lock().unwrap().remove(id)
+    }
+
+    ///Stops the observers and waits until every change they reported is in the cache and sent to the streams,
+    ///which end after that. Dropping the watcher stops the observers without waiting
+    pub async fn stop(mut self) {
+        let observers = self.watches.lock().unwrap().stop_all();
+        futures::future::join_all(observers).await;
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+        if let Some(pump) = self.pump.take() {
+            let _ = pump.await;
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.watches.lock().unwrap().stop_all();
+    }
+}
+
+///Handle to the cache of a `Watcher`, cheap to clone
+#[derive(Clone)]
+pub struct Cache {
+    cacher: Arc<AsyncCacher>,
+}
+
+impl Cache {
+    ///Latest event of every path
+    pub async fn latest(&self) -> Result<HashMap<PathBuf, Data>, String> {
+        self.cacher.get().await
+    }
+
+    ///Events matching `query`, newest first. Needs the SQLite backend
+    pub async fn history(&self, query: HistoryQuery) -> Result<Vec<Event>, String> {
+        self.cacher.history(query).await
+    }
+
+    ///Paths in memory, events in SQLite
+    pub async fn len(&self) -> Result<usize, String> {
+        self.cacher.len().await
+    }
+
+    pub async fn is_empty(&self) -> Result<bool, String> {
+        self.cacher.is_empty().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::watcher::{BuildError, Watcher};
+
+    #[test]
+    fn builder_test() {
+        assert!(matches!(Watcher::builder().start(), Err(BuildError::NoRoots)));
+        assert!(matches!(Watcher::builder().root(".").include("a[").start(), Err(BuildError::Filter(_))));
+    }
+}
@@ -0,0 +1,277 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use futures::StreamExt;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::task::JoinHandle;
+use tracing::{info, info_span, warn, Instrument};
+pub use blazzy_client::models::{WatchError, WatchInfo, WatchSource, WatchStatus};
+use crate::config::Root;
+use crate::duration::HumanDuration;
+use crate::event_bus::SharedEventBus;
+use crate::filter::Filter;
+use crate::observer::{Data, EventSource, ObserverError};
+use crate::status::SharedStatus;
+
+///A watch counts as overflowing for this long after the system dropped changes
+const OVERFLOW_WINDOW: Duration = Duration::from_secs(60);
+///Delay before restarting a failed observer, doubled after every failure up to `MAX_RESTART_DELAY`
+const MIN_RESTART_DELAY: Duration = Duration::from_secs(1);
+const MAX_RESTART_DELAY: Duration = Duration::from_secs(60);
+
+///Shared between a running observer and the manager
+#[derive(Debug)]
+pub struct WatchState {
+    pub filter: RwLock<Filter>,
+    pub stop: AtomicBool,
+    pub events: AtomicU64,
+    pub filtered: AtomicU64,
+    pub overflows: AtomicU64,
+    pub restarts: AtomicU64,
+    last_overflow: Mutex<Option<Instant>>,
+    ///Why the observer stopped, cleared once it runs again
+    error: Mutex<Option<String>>,
+}
+
+impl WatchState {
+    pub fn new(filter: Filter) -> Self {
+        Self {
+            filter: RwLock::new(filter),
+            stop: AtomicBool::new(false),
+            events: AtomicU64::new(0),
+            filtered: AtomicU64::new(0),
+            overflows: AtomicU64::new(0),
+            restarts: AtomicU64::new(0),
+            last_overflow: Mutex::new(None),
+            error: Mutex::new(None),
+        }
+    }
+
+    ///Called when the change buffer overflowed and events were lost
+    pub fn overflowed(&self) {
+        self.overflows.fetch_add(1, Ordering::Relaxed);
+        *self.last_overflow.lock().unwrap() = Some(Instant::now());
+    }
+
+    fn is_overflowing(&self) -> bool {
+        self.last_overflow.lock().unwrap().is_some_and(|at| at.elapsed() < OVERFLOW_WINDOW)
+    }
+
+    pub fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+struct Watch {
+    path: PathBuf,
+    source: WatchSource,
+    state: Arc<WatchState>,
+    task: JoinHandle<()>,
+}
+
+impl Watch {
+    fn info(&self, id: u64) -> WatchInfo {
+        let error = self.state.error();
+        let status = if self.task.is_finished() || error.is_some() {
+            WatchStatus::Failed
+        } else if self.state.is_overflowing() {
+            WatchStatus::Overflowing
+        } else {
+            WatchStatus::Active
+        };
+        WatchInfo {
+            id,
+            path: self.path.clone(),
+            source: self.source,
+            status,
+            events: self.state.events.load(Ordering::Relaxed),
+            filtered: self.state.filtered.load(Ordering::Relaxed),
+            overflows: self.state.overflows.load(Ordering::Relaxed),
+            restarts: self.state.restarts.load(Ordering::Relaxed),
+            error,
+        }
+    }
+}
+
+pub type SharedWatches = Arc<Mutex<WatchManager>>;
+
+///Keeps one observer running per watched root, from the configuration or added at runtime
+pub struct WatchManager {
+    sender: Arc<UnboundedSender<(PathBuf, Data)>>,
+    next_id: u64,
+    watches: BTreeMap<u64, Watch>,
+}
+
+impl WatchManager {
+    pub fn new(sender: Arc<UnboundedSender<(PathBuf, Data)>>) -> Self {
+        Self {
+            sender,
+            next_id: 1,
+            watches: BTreeMap::new(),
+        }
+    }
+
+    ///Stops config watches for roots that are gone, starts new ones and swaps filters of the rest in place
+    pub fn apply(&mut self, roots: &[Root], filters: &Filter) {
+        self.watches.retain(|_, watch| {
+            let keep = watch.source == WatchSource::Api || roots.iter().any(|root| root.path == watch.path);
+            if !keep {
+                info!(path = %watch.path.display(), "Stop observing");
+                watch.state.stop.store(true, Ordering::SeqCst);
+            }
+            keep
+        });
+
+        for root in roots {
+            let filter = filters.merge(&root.filters);
+            match self.watches.values().find(|watch| watch.path == root.path) {
+                Some(watch) => *watch.state.filter.write().unwrap() = filter,
+                None => {
+                    self.start(root.path.clone(), filter, WatchSource::Config);
+                }
+            }
+        }
+    }
+
+    ///Starts observing `path`, `None` when it is already watched
+    pub fn add(&mut self, path: PathBuf, filter: Filter) -> Option<WatchInfo> {
+        if self.watches.values().any(|watch| watch.path == path) {
+            return None;
+        }
+        let id = self.start(path, filter, WatchSource::Api);
+        self.get(id)
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<WatchInfo> {
+        let watch = self.watches.remove(&id)?;
+        info!(path = %watch.path.display(), "Stop observing");
+        watch.state.stop.store(true, Ordering::SeqCst);
+        Some(watch.info(id))
+    }
+
+    ///Stops every watch, the handles finish once the observers noticed
+    pub fn stop_all(&mut self) -> Vec<JoinHandle<()>> {
+        std::mem::take(&mut self.watches).into_values().map(|watch| {
+            watch.state.stop.store(true, Ordering::SeqCst);
+            watch.task
+        }).collect()
+    }
+
+    pub fn get(&self, id: u64) -> Option<WatchInfo> {
+        self.watches.get(&id).map(|watch| watch.info(id))
+    }
+
+    pub fn list(&self) -> Vec<WatchInfo> {
+        self.watches.iter().map(|(id, watch)| watch.info(*id)).collect()
+    }
+
+    fn start(&mut self, path: PathBuf, filter: Filter, source: WatchSource) -> u64 {
+        info!(path = %path.display(), "Start observing");
+        let state = Arc::new(WatchState::new(filter));
+
+        let sender = self.sender.clone();
+        let observer_state = state.clone();
+        let root = path.clone();
+        let span = info_span!("watch", root = %root.display());
+        let task = tokio::task::spawn(Self::observe(root, sender, observer_state).instrument(span));
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.watches.insert(id, Watch { path, source, state, task });
+        id
+    }
+
+    ///Runs the observer until the watch is stopped, restarting it with a growing delay when it fails
+    async fn observe(root: PathBuf, sender: Arc<UnboundedSender<(PathBuf, Data)>>, state: Arc<WatchState>) {
+        let mut attempt = 0;
+        let mut delay = MIN_RESTART_DELAY;
+        while !state.stop.load(Ordering::SeqCst) {
+            let started = Instant::now();
+            let result = match EventSource::open(root.clone(), state.clone()).await {
+                Ok(mut source) => {
+                    if state.error.lock().unwrap().take().is_some() {
+                        info!("Observing again");
+                    }
+                    let mut result = Ok(());
+                    while let Some(change) = source.next().await {
+                        match change {
+                            Ok(change) => if sender.send(change).is_err() {
+                                result = Err(ObserverError::Closed);
+                                break;
+                            },
+                            Err(e) => result = Err(e),
+                        }
+                    }
+                    result
+                }
+                Err(e) => Err(e),
+            };
+
+            let error = match result {
+                Ok(()) => break,
+                Err(ObserverError::Closed) => break,
+                Err(e) => e,
+            };
+            // An observer that ran for a while failed on its own, not because the last restart did
+            if started.elapsed() > MAX_RESTART_DELAY {
+                attempt = 0;
+                delay = MIN_RESTART_DELAY;
+            }
+            attempt += 1;
+
+            warn!(error = %error, attempt, retry_in = %HumanDuration(delay), "Observer failed, restarting");
+            *state.error.lock().unwrap() = Some(error.to_string());
+            SharedStatus.watch_error(&root, error.to_string());
+            SharedEventBus.publish_error(WatchError {
+                path: root.clone(),
+                message: error.to_string(),
+                attempt,
+                retry_in: HumanDuration(delay).to_string(),
+            });
+
+            let restart_at = Instant::now() + delay;
+            while !state.stop.load(Ordering::SeqCst) && Instant::now() < restart_at {
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            if state.stop.load(Ordering::SeqCst) {
+                break;
+            }
+            state.restarts.fetch_add(1, Ordering::Relaxed);
+            delay = (delay * 2).min(MAX_RESTART_DELAY);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::sync::Arc;
+    use tokio::sync::mpsc::unbounded_channel;
+    use crate::config::Root;
+    use crate::filter::{Filter, Patterns};
+    use crate::watches::WatchManager;
+
+    fn filter(include: &[&str]) -> Filter {
+        Filter::new(Patterns { include: include.iter().map(|glob| glob.to_string()).collect(), exclude: vec![] }).unwrap()
+    }
+
+    #[tokio::test]
+    async fn reload_filter_test() {
+        let mut manager = WatchManager::new(Arc::new(unbounded_channel().0));
+        let roots = [Root { path: "blazzy-reload-test".into(), filters: filter(&["**/*.md"]) }];
+        let allows = |manager: &WatchManager, path: &str| manager.watches.values().next().unwrap().state.filter.read().unwrap().allows(Path::new(path));
+
+        manager.apply(&roots, &filter(&["src/**"]));
+        assert!(allows(&manager, "src/README.md"));
+        assert!(!allows(&manager, "docs/README.md"));
+        assert!(!allows(&manager, "src/main.rs"));
+
+        manager.apply(&roots, &filter(&["docs/**"]));
+        assert_eq!(manager.list().len(), 1);
+        assert!(allows(&manager, "docs/README.md"));
+        assert!(!allows(&manager, "src/README.md"));
+        manager.stop_all();
+    }
+}
@@ -0,0 +1,375 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Deserializer};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use tokio::sync::Notify;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{error, info_span, warn, Instrument};
+use crate::duration::HumanDuration;
+use crate::shutdown::{Phase, SharedShutdown};
+use crate::event_bus::SharedEventBus;
+use crate::observer::{Action, Data};
+use crate::metrics;
+use crate::status::SharedStatus;
+
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+///Attempts before a batch the endpoint keeps refusing with a client error is moved to `failed/`
+const MAX_REJECTIONS: u32 = 3;
+
+///Webhook given on the command line as `URL[;secret=SECRET][;path=PREFIX,...][;action=ACTION,...]`
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Webhook {
+    #[serde(deserialize_with = "http_url")]
+    url: String,
+    #[serde(default)]
+    secret: Option<String>,
+    #[serde(default)]
+    paths: Vec<PathBuf>,
+    #[serde(default)]
+    actions: Vec<Action>,
+}
+
+impl Webhook {
+    fn matches(&self, path_buf: &Path, data: &Data) -> bool {
+        (self.paths.is_empty() || self.paths.iter().any(|prefix| path_buf.starts_with(prefix)))
+            && (self.actions.is_empty() || self.actions.contains(&data.action()))
+    }
+
+    ///Stable directory name for the webhook's spooled batches, webhooks to the same url with other
+    ///filters or secrets get their own. Same as before for a bare url, so its spool is still found
+    fn id(&self) -> String {
+        let mut hasher = Sha256::new();
+        hasher.update(self.url.as_bytes());
+        if let Some(secret) = &self.secret {
+            hasher.update(format!("\nsecret={}", secret));
+        }
+        for path in &self.paths {
+            hasher.update(format!("\npath={}", path.display()));
+        }
+        for action in &self.actions {
+            hasher.update(format!("\naction={}", String::from(*action)));
+        }
+        hasher.finalize()[..8].iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    fn sign(&self, body: &[u8]) -> Option<String> {
+        let secret = self.secret.as_ref()?;
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+        let signature = mac.finalize().into_bytes().iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        Some(format!("sha256={}", signature))
+    }
+}
+
+impl FromStr for Webhook {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(';');
+        let url = parts.next().unwrap_or_default().to_string();
+        check_url(&url)?;
+
+        let mut webhook = Self { url, secret: None, paths: vec![], actions: vec![] };
+        for part in parts {
+            match part.split_once('=') {
+                Some(("secret", secret)) => webhook.secret = Some(secret.to_string()),
+                Some(("path", paths)) => webhook.paths = paths.split(',').map(PathBuf::from).collect(),
+                Some(("action", actions)) => {
+                    webhook.actions = actions.split(',').map(Action::from_str).collect::<Result<_, _>>()?
+                }
+                _ => return Err(format!("unknown webhook option: {}", part)),
+            }
+        }
+        Ok(webhook)
+    }
+}
+
+fn check_url(url: &str) -> Result<(), String> {
+    if !url.starts_with("http://") && !url.starts_with("https://") {
+        return Err(format!("webhook url must start with http:// or https://: {}", url));
+    }
+    Ok(())
+}
+
+fn http_url<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let url = String::deserialize(deserializer)?;
+    check_url(&url).map_err(serde::de::Error::custom)?;
+    Ok(url)
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookOptions {
+    ///Max events in one request
+    pub batch_size: usize,
+    ///How long to collect events before sending a batch
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub flush_interval: Duration,
+    ///Upper bound of the delay between retries
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub max_backoff: Duration,
+    ///Where undelivered batches are kept
+    pub spool_dir: PathBuf,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> Self {
+        Self {
+            batch_size: 100,
+            flush_interval: Duration::from_millis(1000),
+            max_backoff: Duration::from_secs(300),
+            spool_dir: env::current_exe().unwrap().parent().unwrap().join("webhooks"),
+        }
+    }
+}
+
+///Collects matching events into batches, spools them to disk and delivers them in order.
+///A batch only leaves the spool once the endpoint answered with a success status, so undelivered
+///batches are picked up again after a restart.
+pub struct WebhookDispatcher {
+    webhook: Arc<Webhook>,
+    options: WebhookOptions,
+    spool: PathBuf,
+    pending: Arc<Notify>,
+}
+
+impl WebhookDispatcher {
+    pub fn init(webhook: Webhook, options: WebhookOptions) -> io::Result<Self> {
+        let spool = options.spool_dir.join(webhook.id());
+        fs::create_dir_all(&spool)?;
+        Ok(Self {
+            webhook: Arc::new(webhook),
+            options,
+            spool,
+            pending: Arc::new(Notify::new()),
+        })
+    }
+
+    pub async fn run(self) {
+        let span = info_span!("webhook", url = %self.webhook.url);
+        self.dispatch().instrument(span).await
+    }
+
+    async fn dispatch(self) {
+        let mut events = SharedEventBus.subscribe();
+
+        let deliverer = Deliverer {
+            webhook: self.webhook.clone(),
+            spool: self.spool.clone(),
+            pending: self.pending.clone(),
+            max_backoff: self.options.max_backoff,
+            client: reqwest::Client::new(),
+        };
+        tokio::task::spawn(deliverer.run().in_current_span());
+
+        let mut batch = vec![];
+        let mut sequence = 0u64;
+        let mut flush = tokio::time::interval(self.options.flush_interval);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Ok((key, value)) => {
+                        if self.webhook.matches(&key, &value) {
+                            batch.push(json!({ key.display().to_string(): value }));
+                        }
+                        if batch.len() < self.options.batch_size {
+                            continue;
+                        }
+                    }
+                    Err(RecvError::Lagged(n)) => {
+                        warn!(missed = n, "Webhook fell behind");
+                        metrics::DROPPED.with_label_values(&["webhook"]).inc_by(n);
+                        continue;
+                    }
+                    Err(RecvError::Closed) => break,
+                },
+                _ = flush.tick() => {}
+                _ = SharedShutdown.reached(Phase::Closing) => {
+                    // Undelivered batches are picked up from the spool on the next start
+                    while let Ok((key, value)) = events.try_recv() {
+                        if self.webhook.matches(&key, &value) {
+                            batch.push(json!({ key.display().to_string(): value }));
+                        }
+                    }
+                    if !batch.is_empty() {
+                        if let Err(e) = self.spool(&batch, sequence + 1) {
+                            error!(error = %e, "Failed to spool webhook batch");
+                        }
+                    }
+                    break;
+                }
+            }
+
+            if batch.is_empty() {
+                continue;
+            }
+            sequence += 1;
+            if let Err(e) = self.spool(&batch, sequence) {
+                error!(error = %e, "Failed to spool webhook batch");
+                continue;
+            }
+            batch.clear();
+            self.pending.notify_one();
+        }
+    }
+
+    fn spool(&self, batch: &[Value], sequence: u64) -> io::Result<()> {
+        let name = format!("{:020}-{:010}.json", chrono::Utc::now().timestamp_micros(), sequence);
+        let tmp = self.spool.join(format!("{}.tmp", name));
+        fs::write(&tmp, serde_json::to_vec(batch)?)?;
+        fs::rename(tmp, self.spool.join(name))
+    }
+}
+
+struct Deliverer {
+    webhook: Arc<Webhook>,
+    spool: PathBuf,
+    pending: Arc<Notify>,
+    max_backoff: Duration,
+    client: reqwest::Client,
+}
+
+impl Deliverer {
+    async fn run(self) {
+        loop {
+            match self.spooled() {
+                Ok(batches) => {
+                    for batch in batches {
+                        if self.deliver(&batch).await {
+                            if let Err(e) = fs::remove_file(&batch) {
+                                error!(batch = %batch.display(), error = %e, "Failed to remove delivered webhook batch");
+                            }
+                        } else if let Err(e) = self.fail(&batch) {
+                            error!(batch = %batch.display(), error = %e, "Failed to move undeliverable webhook batch");
+                        }
+                    }
+                }
+                Err(e) => error!(spool = %self.spool.display(), error = %e, "Failed to read webhook spool"),
+            }
+            self.pending.notified().await;
+        }
+    }
+
+    fn spooled(&self) -> io::Result<Vec<PathBuf>> {
+        let mut batches = fs::read_dir(&self.spool)?
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+            .collect::<Vec<_>>();
+        batches.sort();
+        Ok(batches)
+    }
+
+    ///Keeps a batch the endpoint refused next to the spool, for inspection or to be moved back by hand
+    fn fail(&self, batch: &Path) -> io::Result<()> {
+        let failed = self.spool.join("failed");
+        fs::create_dir_all(&failed)?;
+        fs::rename(batch, failed.join(batch.file_name().unwrap_or_default()))
+    }
+
+    ///Retries with exponential backoff until the endpoint accepts the batch, false once it refused it
+    ///`MAX_REJECTIONS` times with a client error other than 408 or 429
+    async fn deliver(&self, batch: &Path) -> bool {
+        let body = match fs::read(batch) {
+            Ok(body) => body,
+            Err(e) => {
+                error!(batch = %batch.display(), error = %e, "Failed to read webhook batch");
+                return false;
+            }
+        };
+        let delivery = batch.file_stem().unwrap_or_default().to_string_lossy().to_string();
+
+        let mut backoff = INITIAL_BACKOFF;
+        let mut rejections = 0;
+        loop {
+            let mut request = self.client.post(&self.webhook.url)
+                .header("Content-Type", "application/json")
+                .header("X-Blazzy-Delivery", &delivery)
+                .body(body.clone());
+            if let Some(signature) = self.webhook.sign(&body) {
+                request = request.header("X-Blazzy-Signature", signature);
+            }
+
+            match request.send().await {
+                Ok(res) if res.status().is_success() => return true,
+                Ok(res) if is_permanent(res.status()) => {
+                    rejections += 1;
+                    SharedStatus.error(format!("webhook {} answered {}", self.webhook.url, res.status()));
+                    if rejections >= MAX_REJECTIONS {
+                        error!(status = res.status().as_u16(), batch = %delivery, "Webhook refused the batch, moved to failed");
+                        metrics::WEBHOOK_FAILED.inc();
+                        return false;
+                    }
+                    warn!(status = res.status().as_u16(), retry_in = %HumanDuration(backoff), "Webhook refused the batch, retrying");
+                }
+                Ok(res) => {
+                    warn!(status = res.status().as_u16(), retry_in = %HumanDuration(backoff), "Webhook rejected the batch, retrying");
+                    SharedStatus.error(format!("webhook {} answered {}", self.webhook.url, res.status()));
+                }
+                Err(e) => {
+                    warn!(error = %e, retry_in = %HumanDuration(backoff), "Webhook failed, retrying");
+                    SharedStatus.error(format!("webhook {} failed: {}", self.webhook.url, e));
+                }
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(self.max_backoff);
+        }
+    }
+}
+
+///Client errors that won't go away by sending the same batch again
+fn is_permanent(status: reqwest::StatusCode) -> bool {
+    status.is_client_error() && status != reqwest::StatusCode::REQUEST_TIMEOUT && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use reqwest::StatusCode;
+    use crate::observer::{Action, Data};
+    use crate::webhook::{is_permanent, Webhook};
+
+    #[test]
+    fn parse_and_match_test() {
+        let webhook = "https://example.com/hook;secret=abc;path=/srv;action=created,Modified".parse::<Webhook>().unwrap();
+        assert_eq!(webhook.secret.as_deref(), Some("abc"));
+        assert!(webhook.matches(&PathBuf::from("/srv/a.txt"), &Data::new(Action::Created, None)));
+        assert!(!webhook.matches(&PathBuf::from("/srv/a.txt"), &Data::new(Action::Deleted, None)));
+        assert!(!webhook.matches(&PathBuf::from("/etc/a.txt"), &Data::new(Action::Modified, None)));
+        assert!("ftp://example.com".parse::<Webhook>().is_err());
+    }
+
+    #[test]
+    fn id_test() {
+        let bare = "https://example.com/hook".parse::<Webhook>().unwrap();
+        let filtered = "https://example.com/hook;path=/srv".parse::<Webhook>().unwrap();
+        let signed = "https://example.com/hook;secret=abc".parse::<Webhook>().unwrap();
+        assert_ne!(bare.id(), filtered.id());
+        assert_ne!(bare.id(), signed.id());
+        assert_eq!(bare.id(), "https://example.com/hook".parse::<Webhook>().unwrap().id());
+    }
+
+    #[test]
+    fn permanent_test() {
+        assert!(is_permanent(StatusCode::NOT_FOUND));
+        assert!(!is_permanent(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_permanent(StatusCode::REQUEST_TIMEOUT));
+        assert!(!is_permanent(StatusCode::BAD_GATEWAY));
+    }
+
+    #[test]
+    fn sign_test() {
+        let webhook = "http://localhost/hook;secret=key".parse::<Webhook>().unwrap();
+        assert_eq!(
+            webhook.sign(b"The quick brown fox jumps over the lazy dog").unwrap(),
+            "sha256=f7bc83f430538424b13298e6aa6fb143ef4d59a14946175997479dbc2d1a3cd8"
+        );
+    }
+}
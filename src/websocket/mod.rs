@@ -1,38 +1,310 @@
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::time::Duration;
-use actix::{Actor, ActorContext, AsyncContext, Handler, Message, StreamHandler};
+use actix::{Actor, ActorContext, AsyncContext, StreamHandler};
 use actix_web::{Error, HttpRequest, HttpResponse, web};
 use actix_web_actors::ws;
-use serde_json::json;
-use tokio::time::sleep;
-use crate::async_cacher::SharedAsyncCacher;
+use chrono::Local;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use blazzy_client::models::{Patterns, Resume};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tracing::{info, info_span, warn, Instrument, Span};
+use crate::async_cacher::AsyncCacher;
+use crate::auth::Scope;
+use crate::event_bus::SharedEventBus;
+use crate::filter::Filter;
+use crate::metrics;
+use crate::observer::Data;
+use crate::shutdown::{Phase, SharedShutdown};
+use crate::status::SharedStatus;
+use crate::storage::{now_millis, parse_time, HistoryQuery};
+use crate::watches::WatchError;
 
-pub struct WebSocket;
+///What to do with a client whose outgoing queue is full
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum OverflowPolicy {
+    ///Drop the oldest queued event to make room for the new one
+    DropOldest,
+    ///Close the connection
+    Disconnect,
+    ///Merge queued events for the same path, keeping only the latest
+    Coalesce,
+}
+
+impl OverflowPolicy {
+    fn name(&self) -> &'static str {
+        match self {
+            OverflowPolicy::DropOldest => "drop-oldest",
+            OverflowPolicy::Disconnect => "disconnect",
+            OverflowPolicy::Coalesce => "coalesce",
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct WsOptions {
+    ///Max events queued per client before the overflow policy applies
+    pub queue_size: usize,
+    ///Max events sent to a client in one frame
+    pub batch_size: usize,
+    ///Interval between frames
+    #[serde(deserialize_with = "crate::duration::deserialize")]
+    pub flush_interval: Duration,
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for WsOptions {
+    fn default() -> Self {
+        Self {
+            queue_size: 1024,
+            batch_size: 100,
+            flush_interval: Duration::from_millis(10),
+            overflow_policy: OverflowPolicy::DropOldest,
+        }
+    }
+}
+
+///Numbers connections in logs
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+
+///Which changes a client receives, narrowed with the query string of its request
+#[derive(Clone)]
+pub struct Subscription {
+    scope: Scope,
+    ///Only changes under this path
+    path: Option<PathBuf>,
+    filter: Filter,
+    ///Replay the history from this Unix time in milliseconds before the live changes
+    since: Option<i64>,
+}
+
+impl Subscription {
+    ///Everything the scope allows
+    pub fn new(scope: Scope) -> Self {
+        Self { scope, path: None, filter: Filter::default(), since: None }
+    }
+
+    ///`path`, `include` and `exclude` (both repeatable) and `since`, an RFC 3339 time or a duration before now
+    fn parse(scope: Scope, query: &str) -> Result<Self, String> {
+        let pairs = web::Query::<Vec<(String, String)>>::from_query(query).map_err(|e| e.to_string())?.into_inner();
+        let mut subscription = Self::new(scope);
+        let mut patterns = Patterns::default();
+        for (key, value) in pairs {
+            match key.as_str() {
+                "path" => subscription.path = Some(PathBuf::from(value)),
+                "include" => patterns.include.push(value),
+                "exclude" => patterns.exclude.push(value),
+                "since" => subscription.since = Some(parse_time(&value)?),
+                "token" => {}
+                _ => return Err(format!("unknown parameter `{}`", key)),
+            }
+        }
+        subscription.filter = Filter::new(patterns).map_err(|e| format!("invalid filter: {}", e))?;
+        Ok(subscription)
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        self.scope.allows(path)
+            && self.path.as_ref().is_none_or(|prefix| path.starts_with(prefix))
+            && self.filter.allows(path)
+    }
+}
+
+pub struct WebSocket {
+    options: WsOptions,
+    span: Span,
+    subscription: Subscription,
+    ///Where history is replayed from when the subscription has `since`
+    cacher: Arc<AsyncCacher>,
+    queue: Arc<Mutex<ClientQueue>>,
+    ///This client's share of the queue depth metric
+    reported_depth: i64,
+}
+
+impl WebSocket {
+    pub fn new(options: WsOptions, subscription: Subscription, cacher: Arc<AsyncCacher>, peer: &str) -> Self {
+        Self {
+            options,
+            span: info_span!("client", id = NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed), peer),
+            subscription,
+            cacher,
+            queue: Arc::new(Mutex::new(ClientQueue::default())),
+            reported_depth: 0,
+        }
+    }
+
+    fn flush(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        let mut queue = self.queue.lock().unwrap();
+
+        if queue.overflowed {
+            warn!(parent: &self.span, queue_size = self.options.queue_size, "Disconnecting client that fell behind");
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Policy,
+                description: Some(format!("Outgoing queue exceeded {} events", self.options.queue_size)),
+            }));
+            ctx.stop();
+            return;
+        }
+
+        if queue.dropped > 0 || queue.coalesced > 0 {
+            ctx.text(json!({
+                "overflow": {
+                    "policy": self.options.overflow_policy.name(),
+                    "dropped": queue.dropped,
+                    "coalesced": queue.coalesced,
+                }
+            }).to_string());
+            metrics::DROPPED.with_label_values(&["websocket"]).inc_by(queue.dropped);
+            metrics::COALESCED.inc_by(queue.coalesced);
+            queue.dropped = 0;
+            queue.coalesced = 0;
+        }
+
+        if let Some(resume) = queue.resume.take() {
+            ctx.text(json!({ "resume": resume }).to_string());
+        }
+
+        for error in queue.errors.drain(..) {
+            ctx.text(json!({ "error": error }).to_string());
+        }
+
+        // Once the server is shutting down everything left is sent at once before closing
+        loop {
+            let count = queue.events.len().min(self.options.batch_size);
+            if count == 0 {
+                break;
+            }
+
+            let batch = queue.events.drain(..count)
+                .map(|(key, value, time)| {
+                    if let Some(age) = value.age() {
+                        metrics::DELIVERY_LATENCY.observe(age.as_secs_f64());
+                    }
+                    json!({ key.display().to_string(): Change { data: &value, time: &time } })
+                })
+                .collect::<Vec<Value>>();
+            ctx.text(Value::Array(batch).to_string());
+            if !queue.closing {
+                break;
+            }
+        }
+
+        let depth = queue.events.len() as i64;
+        metrics::WS_QUEUE.add(depth - self.reported_depth);
+        self.reported_depth = depth;
+
+        if queue.closing {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Away,
+                description: Some("Server is shutting down".to_string()),
+            }));
+            ctx.stop();
+        }
+    }
+}
 
 impl Actor for WebSocket {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
         ctx.text("Connected");
-        let ctx = ctx.address();
+        info!(parent: &self.span, "Client connected");
+        SharedStatus.client_connected();
+        metrics::WS_CLIENTS.inc();
 
-        let cacher = SharedAsyncCacher.clone();
+        let queue = self.queue.clone();
+        let queue_size = self.options.queue_size;
+        let policy = self.options.overflow_policy;
+        let subscription = self.subscription.clone();
+        let cacher = self.cacher.clone();
+        let mut events = SharedEventBus.subscribe();
+        let mut errors = SharedEventBus.subscribe_errors();
+        // Later changes arrive through `events`
+        let subscribed = now_millis();
+
+        actix::spawn(async move {
+            if let Some(since) = subscription.since {
+                let query = HistoryQuery {
+                    path: subscription.path.clone(),
+                    action: None,
+                    since: Some(since),
+                    until: Some(subscribed),
+                    before: None,
+                    limit: queue_size,
+                    scope: subscription.scope.clone(),
+                };
+                let history = cacher.history(query).await;
+                let mut queue = queue.lock().unwrap();
+                queue.resume = Some(match history {
+                    Ok(history) => {
+                        let complete = history.len() < queue_size;
+                        let history = history.into_iter().rev().filter(|event| subscription.allows(&event.path)).collect::<Vec<_>>();
+                        info!(events = history.len(), complete, "Replaying history");
+                        let resume = Resume::Replayed { events: history.len(), complete };
+                        for event in history {
+                            queue.push(event.path, event.data, event.time, queue_size, policy);
+                        }
+                        resume
+                    }
+                    Err(error) => Resume::Failed { error },
+                });
+            }
 
-        actix::spawn( async move {
             loop {
-                if !cacher.is_empty().await {
-                    if let Some((key, value)) = cacher.pop().await {
-                        ctx.send( TaskerMessage(
-                            json!({
-                                key.display().to_string(): value
-                            }).to_string()
-                        )).await.unwrap();
+                let event = tokio::select! {
+                    event = events.recv() => event,
+                    Ok(error) = errors.recv() => {
+                        if subscription.scope.allows(&error.path) {
+                            queue.lock().unwrap().errors.push(error);
+                        }
+                        continue;
                     }
+                    _ = SharedShutdown.reached(Phase::Closing) => {
+                        // Everything published before closing is already in the channel
+                        let mut queue = queue.lock().unwrap();
+                        loop {
+                            match events.try_recv() {
+                                Ok((key, value)) => if subscription.allows(&key) {
+                                    queue.push(key, value, Local::now().to_rfc3339(), queue_size, policy)
+                                },
+                                Err(TryRecvError::Lagged(n)) => queue.dropped += n,
+                                Err(_) => break,
+                            }
+                        }
+                        queue.closing = true;
+                        break;
+                    }
+                };
+                let mut queue = queue.lock().unwrap();
+                if queue.closed {
+                    break;
+                }
+                match event {
+                    Ok((key, value)) => if subscription.allows(&key) {
+                        queue.push(key, value, Local::now().to_rfc3339(), queue_size, policy)
+                    },
+                    Err(RecvError::Lagged(n)) => queue.dropped += n,
+                    Err(RecvError::Closed) => break,
                 }
-                sleep(Duration::from_millis(10)).await
             }
-        });
+        }.instrument(self.span.clone()));
+
+        ctx.run_interval(self.options.flush_interval, |act, ctx| act.flush(ctx));
     }
 
+    fn stopped(&mut self, _: &mut Self::Context) {
+        info!(parent: &self.span, "Client disconnected");
+        self.queue.lock().unwrap().closed = true;
+        SharedStatus.client_disconnected();
+        metrics::WS_CLIENTS.dec();
+        metrics::WS_QUEUE.sub(self.reported_depth);
+    }
 }
 
 impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
@@ -44,18 +316,114 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for WebSocket {
     }
 }
 
-impl Handler<TaskerMessage> for WebSocket {
-    type Result = ();
+pub(crate) async fn ws_index(r: HttpRequest, stream: web::Payload, options: web::Data<WsOptions>, scope: web::ReqData<Scope>, cacher: web::Data<Arc<AsyncCacher>>) -> Result<HttpResponse, Error> {
+    let subscription = match Subscription::parse(scope.into_inner(), r.query_string()) {
+        Ok(subscription) => subscription,
+        Err(e) => return Ok(HttpResponse::BadRequest().json(json!({ "error": e }))),
+    };
+    let peer = r.connection_info().peer_addr().unwrap_or("-").to_string();
+    ws::start(WebSocket::new(options.get_ref().clone(), subscription, cacher.get_ref().clone(), &peer), &r, stream)
+}
 
-    fn handle(&mut self, msg: TaskerMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
-    }
+#[derive(Default)]
+struct ClientQueue {
+    ///Changes with the RFC 3339 time they were recorded
+    events: VecDeque<(PathBuf, Data, String)>,
+    ///Outcome of the history replay, sent ahead of the replayed events
+    resume: Option<Resume>,
+    ///Observer failures, sent ahead of the events
+    errors: Vec<WatchError>,
+    dropped: u64,
+    coalesced: u64,
+    overflowed: bool,
+    closed: bool,
+    ///Flush everything and close, the server is shutting down
+    closing: bool,
+}
+
+///A change in a frame, `time` lets clients resume where they left off by the server's clock
+#[derive(Serialize)]
+struct Change<'a> {
+    #[serde(flatten)]
+    data: &'a Data,
+    time: &'a str,
 }
 
-pub(crate) async fn ws_index(r: HttpRequest, stream: web::Payload) -> Result<HttpResponse, Error> {
-    ws::start(WebSocket, &r, stream)
+impl ClientQueue {
+    fn push(&mut self, path_buf: PathBuf, data: Data, time: String, limit: usize, policy: OverflowPolicy) {
+        if self.events.len() >= limit {
+            match policy {
+                OverflowPolicy::DropOldest => {
+                    self.events.pop_front();
+                    self.dropped += 1;
+                }
+                OverflowPolicy::Disconnect => {
+                    self.overflowed = true;
+                    return;
+                }
+                OverflowPolicy::Coalesce => {
+                    self.coalesce(&path_buf);
+                    if self.events.len() >= limit {
+                        self.events.pop_front();
+                        self.dropped += 1;
+                    }
+                }
+            }
+        }
+        self.events.push_back((path_buf, data, time));
+    }
+
+    ///Keeps only the latest queued event per path, `incoming` is about to be pushed so it is dropped too
+    fn coalesce(&mut self, incoming: &Path) {
+        let before = self.events.len();
+        let mut keep = vec![false; before];
+        let mut seen = HashSet::from([incoming]);
+        for (i, (key, ..)) in self.events.iter().enumerate().rev() {
+            keep[i] = seen.insert(key.as_path());
+        }
+        let mut keep = keep.into_iter();
+        self.events.retain(|_| keep.next().unwrap());
+        self.coalesced += (before - self.events.len()) as u64;
+    }
 }
 
-#[derive(Message)]
-#[rtype(result = "()")]
-pub struct TaskerMessage(pub(crate) String);
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use crate::observer::{Action, Data};
+    use crate::websocket::{ClientQueue, OverflowPolicy};
+
+    const TIME: &str = "2024-05-01T12:00:00+02:00";
+
+    #[test]
+    fn drop_oldest_test() {
+        let mut queue = ClientQueue::default();
+        for i in 0..3 {
+            queue.push(PathBuf::from(i.to_string()), Data::new(Action::Created, None), TIME.to_string(), 2, OverflowPolicy::DropOldest);
+        }
+        assert_eq!(queue.events.len(), 2);
+        assert_eq!(queue.events[0].0, PathBuf::from("1"));
+        assert_eq!(queue.dropped, 1);
+    }
+
+    #[test]
+    fn coalesce_test() {
+        let mut queue = ClientQueue::default();
+        queue.push(PathBuf::from("a"), Data::new(Action::Created, None), TIME.to_string(), 2, OverflowPolicy::Coalesce);
+        queue.push(PathBuf::from("b"), Data::new(Action::Created, None), TIME.to_string(), 2, OverflowPolicy::Coalesce);
+        queue.push(PathBuf::from("a"), Data::new(Action::Modified, None), TIME.to_string(), 2, OverflowPolicy::Coalesce);
+        assert_eq!(queue.events.len(), 2);
+        assert_eq!(queue.events[1], (PathBuf::from("a"), Data::new(Action::Modified, None), TIME.to_string()));
+        assert_eq!(queue.coalesced, 1);
+        assert_eq!(queue.dropped, 0);
+    }
+
+    #[test]
+    fn disconnect_test() {
+        let mut queue = ClientQueue::default();
+        queue.push(PathBuf::from("a"), Data::new(Action::Created, None), TIME.to_string(), 1, OverflowPolicy::Disconnect);
+        queue.push(PathBuf::from("b"), Data::new(Action::Created, None), TIME.to_string(), 1, OverflowPolicy::Disconnect);
+        assert!(queue.overflowed);
+        assert_eq!(queue.events.len(), 1);
+    }
+}